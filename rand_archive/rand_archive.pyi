@@ -1,4 +1,4 @@
-from typing import Any, Tuple, Union, Optional
+from typing import Any, List, Literal, Sequence, Tuple, Union, Optional
 
 class Header:
     @classmethod
@@ -22,15 +22,114 @@ class Writer:
 
 class Reader:
     def __new__(cls) -> 'Reader': ...
-    def open_file(self, path: str) -> 'Reader': ...
+    def open_file(self, path: str, mmap: bool = False) -> 'Reader': ...
+    def open_files(self, paths: List[str]) -> 'Reader': ...
+    def open_glob(self, pattern: str) -> 'Reader': ...
+    def open_manifest(self, path: str) -> 'Reader': ...
+    def with_max_open_files(self, max_open: int) -> 'Reader': ...
     def open_gcs(self, uri: str) -> 'Reader': ...
+    def open_http(self, url: str, header_prefetch: Optional[int] = (1 << 20) + 8) -> 'Reader': ...
+    def open_s3(self, uri: str, endpoint: Optional[str] = None, region: Optional[str] = None, path_style: bool = False, access_key: Optional[str] = None, secret_key: Optional[str] = None, session_token: Optional[str] = None) -> 'Reader': ...
     def by_size(self, size: int) -> 'Reader': ...
     def by_count(self, count: int) -> 'Reader': ...
+    def shuffle_buffer_by_size(self, size: int) -> 'Reader': ...
+    def shuffle_buffer_by_count(self, count: int) -> 'Reader': ...
     def with_shuffling(self, seed: Optional[int]) -> 'Reader': ...
+    def set_epoch(self, epoch: int) -> 'Reader': ...
+    def filter_prefix(self, prefix: str) -> 'Reader': ...
+    def filter_regex(self, pattern: str) -> 'Reader': ...
+    def filter_keys(self, keys: List[str]) -> 'Reader': ...
+    def exclude_keys(self, keys: List[str]) -> 'Reader': ...
+    def filter_size(self, min_size: int = 0, max_size: Optional[int] = None) -> 'Reader': ...
+    def with_sample_weights(self, weights: Sequence[float]) -> 'Reader': ...
+    def with_stored_weights(self, key: str) -> 'Reader': ...
+    def with_replacement(self, num_samples: Optional[int] = None) -> 'Reader': ...
+    def sample_by_block(self) -> 'Reader': ...
+    def with_random_window(self, len: int, align: int = 1) -> 'Reader': ...
+    def repeat(self, epochs: int) -> 'Reader': ...
+    def repeat_forever(self) -> 'Reader': ...
     def with_sharding(self, rank: int, world_size: int) -> 'Reader': ...
+    def with_nested_sharding(self, rank: int, world_size: int) -> 'Reader': ...
+    def with_shard_mode(self, mode: Literal['uneven', 'drop_remainder', 'pad']) -> 'Reader': ...
+    def balance_shards_by_size(self) -> 'Reader': ...
+    def __len__(self) -> int: ...
+    def read_range(self, key: str, start: int, end: int) -> bytes: ...
+    def with_retries(self, max_retries: int = 3, backoff: float = 0.1, max_backoff: float = 10.0, jitter: float = 0.5, timeout: Optional[float] = None, hedge_after: Optional[float] = None) -> 'Reader': ...
+    def with_disk_cache(self, dir: str, max_bytes: int, chunk_size: int = 4 << 20) -> 'Reader': ...
+    def with_block_cache(self, capacity: int) -> 'Reader': ...
+    def with_coalescing(self, max_bytes: int = 8 << 20, max_gap: int = 64 << 10) -> 'Reader': ...
     def with_buffering(self, buffer_size: Optional[int]) -> 'Reader': ...
+    def with_error_policy(self, policy: Literal['raise', 'skip_sample', 'skip_block', 'retry'], retries: int = 3) -> 'Reader': ...
     def __iter__(self) -> 'EntryIter': ...
+    def iter_from(self, state: 'ReaderState') -> 'EntryIter': ...
+    def iter_batches(self, batch_size: int, drop_last: bool = False, state: Optional['ReaderState'] = None) -> 'BatchIter': ...
+    def iter_buckets(self, batch_size: int, boundaries: Optional[List[int]] = None, max_waste: Optional[float] = None, drop_last: bool = False, state: Optional['BucketState'] = None) -> 'BucketIter': ...
+    def iter_packed(self, seq_len: int, dtype: Literal['uint16', 'uint32'] = 'uint16', separator: Optional[int] = None, segments: bool = False, drop_last: bool = True, state: Optional['PackState'] = None) -> 'PackIter': ...
+
+class ReaderState:
+    def __new__(cls, state: bytes) -> 'ReaderState': ...
+    @property
+    def epoch(self) -> int: ...
+    def to_bytes(self) -> bytes: ...
+    def __reduce__(self) -> Tuple[Any, Tuple[bytes]]: ...
+
+class Dataset:
+    def __new__(cls, path: str, cache_size: Optional[int] = None) -> 'Dataset': ...
+    def __len__(self) -> int: ...
+    def __contains__(self, key: str) -> bool: ...
+    def __getitem__(self, index: Union[int, str]) -> Tuple[str, bytes]: ...
+    def __reduce__(self) -> Tuple[Any, Tuple[str, Optional[int]]]: ...
 
 class EntryIter:
     def __iter__(self) -> 'EntryIter': ...
-    def __next__(self) -> Union[None, Tuple[str, bytes]]: ...
\ No newline at end of file
+    @property
+    def epoch(self) -> int: ...
+    def state(self) -> ReaderState: ...
+    def __next__(self) -> Union[None, Tuple[str, bytes]]: ...
+
+class BatchIter:
+    def __iter__(self) -> 'BatchIter': ...
+    @property
+    def epoch(self) -> int: ...
+    def state(self) -> ReaderState: ...
+    def __next__(self) -> Tuple[List[str], bytes, List[int]]: ...
+
+class BucketState:
+    def __new__(cls, state: bytes) -> 'BucketState': ...
+    def to_bytes(self) -> bytes: ...
+    def __reduce__(self) -> Tuple[Any, Tuple[bytes]]: ...
+
+class BucketIter:
+    def __iter__(self) -> 'BucketIter': ...
+    @property
+    def epoch(self) -> int: ...
+    def state(self) -> BucketState: ...
+    def __next__(self) -> Tuple[List[str], bytes, List[int]]: ...
+
+class PackState:
+    def __new__(cls, state: bytes) -> 'PackState': ...
+    def to_bytes(self) -> bytes: ...
+    def __reduce__(self) -> Tuple[Any, Tuple[bytes]]: ...
+
+class PackIter:
+    def __iter__(self) -> 'PackIter': ...
+    @property
+    def epoch(self) -> int: ...
+    def state(self) -> PackState: ...
+    def __next__(self) -> Tuple[bytes, Optional[List[int]]]: ...
+
+class Mixer:
+    def __new__(cls, sources: List[Tuple[Reader, float]], seed: int = 0, mode: Literal['exhaust', 'repeat'] = 'exhaust') -> 'Mixer': ...
+    def with_sharding(self, rank: int, world_size: int) -> 'Mixer': ...
+    def __iter__(self) -> 'MixIter': ...
+    def iter_from(self, state: 'MixState') -> 'MixIter': ...
+
+class MixState:
+    def __new__(cls, state: bytes) -> 'MixState': ...
+    def to_bytes(self) -> bytes: ...
+    def __reduce__(self) -> Tuple[Any, Tuple[bytes]]: ...
+
+class MixIter:
+    def __iter__(self) -> 'MixIter': ...
+    def state(self) -> MixState: ...
+    def __next__(self) -> Union[None, Tuple[str, bytes]]: ...
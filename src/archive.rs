@@ -146,7 +146,7 @@ mod tests {
         let dummy_writer = new_dummy_file(10, 100).unwrap();
         let dummy_header = dummy_writer.header().clone();
         let loaded_writer = Writer::load(dummy_writer.file.try_clone().unwrap(), 1024).unwrap();
-        assert_eq!(*dummy_writer.header(), *loaded_writer.header());
+        assert_eq!(dummy_header, *loaded_writer.header());
         assert_eq!(1000, loaded_writer.data_size);
     }
 }
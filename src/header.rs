@@ -98,6 +98,11 @@ impl Header {
         self.get_index(idx).map(|(_, entry)| entry.end_idx() + self.byte_size())
     }
 
+    /// Byte size of the whole archive, header included.
+    pub fn archive_size(&self) -> usize {
+        self.byte_size() + self.entries.values().map(SampleMD::end_idx).max().unwrap_or(0)
+    }
+
     pub fn byte_range_of(&self, range: &Range<usize>) -> Option<Range<usize>> {
         Some(self.byte_start_of(range.start)?..self.byte_end_of(range.end - 1)?)
     }
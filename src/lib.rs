@@ -12,7 +12,7 @@ pub mod test_setup {
     use std::sync::Once;
 
     use rand::Rng;
-    use tempfile::tempfile;
+    use tempfile::{tempfile, NamedTempFile};
 
     use super::*;
     use crate::archive::Writer;
@@ -55,4 +55,18 @@ pub mod test_setup {
         writer.close()?;
         Ok(writer)
     }
+
+    pub fn new_dummy_path(entries_count: usize, value_size: usize) -> Result<NamedTempFile> {
+        let file = NamedTempFile::new()?;
+        let mut writer = Writer::new(file.as_file().try_clone()?, 1024, 10 * 1024)?;
+
+        for i in 0..entries_count {
+            let key = format!("{i:06}");
+            let value = generate_random_value(value_size);
+            writer.write(&key, &value)?;
+        }
+
+        writer.close()?;
+        Ok(file)
+    }
 }
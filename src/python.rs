@@ -1,8 +1,12 @@
+#![allow(non_local_definitions)]
+
 use std::fs::OpenOptions;
+use std::process;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use bytes::Bytes;
-use pyo3::exceptions::PyKeyError;
+use color_eyre::eyre::{bail, Report, WrapErr};
+use pyo3::exceptions::{PyIOError, PyImportError, PyIndexError, PyKeyError, PyRuntimeError};
 use pyo3::prelude::*;
 use pyo3::types::{PyBytes, PyTuple, PyType};
 use pyo3::PyErr;
@@ -10,7 +14,15 @@ use pyo3::PyErr;
 use super::*;
 use crate::archive::Writer;
 use crate::header::{Header, SampleMD};
-use crate::reader::Reader;
+#[cfg(feature = "http")]
+use crate::reader::HeaderFetch;
+#[cfg(feature = "s3")]
+use crate::reader::S3Config;
+use crate::reader::{
+    Batch, BatchIter, BucketIter, BucketState, Bucketing, Dataset, DatasetError, ErrorPolicy, MixIter, MixState, Mixer,
+    PackIter, PackState, Packing, ReadError, Reader, ReaderState, RetryPolicy, Sample, SampleIter, Sequence,
+    TokenWidth,
+};
 
 const DEF_CACHE_SIZE: usize = 100 * 1024 * 1024;
 const DEF_HEADER_SIZE: usize = 1024 * 1024;
@@ -29,12 +41,12 @@ pub struct PyHeader {
 #[pymethods]
 impl PyHeader {
     #[classmethod]
-    fn calc_header_size(cls: &PyType, key_size: usize, n_entries: usize) -> usize {
+    fn calc_header_size(_cls: &PyType, key_size: usize, n_entries: usize) -> usize {
         n_entries * (key_size * 8 + 16)
     }
 
     #[classmethod]
-    fn load(cls: &PyType, path: &str) -> PyResult<Self> {
+    fn load(_cls: &PyType, path: &str) -> PyResult<Self> {
         let mut file = OpenOptions::new()
             .read(true)
             .open(path)
@@ -86,7 +98,7 @@ impl PyWriter {
 
     #[classmethod]
     #[pyo3(signature = (path, cache_size=DEF_CACHE_SIZE))]
-    fn load(cls: &PyType, path: &str, cache_size: usize) -> Result<Self> {
+    fn load(_cls: &PyType, path: &str, cache_size: usize) -> Result<Self> {
         let file = OpenOptions::new().read(true).write(true).open(path)?;
         let inner = Writer::load(file, cache_size)?;
         Ok(PyWriter { inner })
@@ -104,12 +116,12 @@ impl PyWriter {
         slf
     }
 
-    fn __exit__(mut slf: PyRefMut<'_, Self>, exc_type: &PyAny, exc_value: &PyAny, exc_traceback: &PyAny) -> Result<()> {
+    fn __exit__(mut slf: PyRefMut<'_, Self>, _exc_type: &PyAny, _exc_value: &PyAny, _exc_traceback: &PyAny) -> Result<()> {
         slf.close()
     }
 }
 
-#[pyclass[name = "Reader", unsendable]]
+#[pyclass[name = "Reader"]]
 struct PyReader {
     inner: Reader,
 }
@@ -121,8 +133,45 @@ impl PyReader {
         Self { inner: Reader::new() }
     }
 
-    fn open_file<'a>(mut slf: PyRefMut<'a, Self>, path: &str) -> Result<PyRefMut<'a, Self>> {
-        slf.inner.open_file(path)?;
+    #[pyo3(signature = (path, mmap=false))]
+    fn open_file<'a>(mut slf: PyRefMut<'a, Self>, path: &str, mmap: bool) -> Result<PyRefMut<'a, Self>> {
+        match mmap {
+            true => slf.inner.open_file_mmap(path)?,
+            false => slf.inner.open_file(path)?,
+        };
+        Ok(slf)
+    }
+
+    fn open_files(mut slf: PyRefMut<'_, Self>, paths: Vec<String>) -> Result<PyRefMut<'_, Self>> {
+        slf.inner.open_files(&paths)?;
+        Ok(slf)
+    }
+
+    fn open_glob<'a>(mut slf: PyRefMut<'a, Self>, pattern: &str) -> Result<PyRefMut<'a, Self>> {
+        slf.inner.open_glob(pattern)?;
+        Ok(slf)
+    }
+
+    fn open_manifest<'a>(mut slf: PyRefMut<'a, Self>, path: &str) -> Result<PyRefMut<'a, Self>> {
+        slf.inner.open_manifest(path)?;
+        Ok(slf)
+    }
+
+    fn with_max_open_files(mut slf: PyRefMut<'_, Self>, max_open: usize) -> PyRefMut<'_, Self> {
+        slf.inner.with_max_open_files(max_open);
+        slf
+    }
+
+    /// Fetches the header in one request of `header_prefetch` bytes if it fits, or exactly if it is `None`.
+    #[cfg(feature = "http")]
+    #[pyo3(signature = (url, header_prefetch=(1 << 20) + 8))]
+    fn open_http<'a>(
+        mut slf: PyRefMut<'a, Self>,
+        url: &str,
+        header_prefetch: Option<usize>,
+    ) -> Result<PyRefMut<'a, Self>> {
+        let fetch = header_prefetch.map_or(HeaderFetch::Exact, HeaderFetch::Prefetch);
+        slf.inner.open_http(url, fetch)?;
         Ok(slf)
     }
 
@@ -132,6 +181,39 @@ impl PyReader {
         Ok(slf)
     }
 
+    /// Settings not given are read from the `AWS_*` environment variables.
+    #[cfg(feature = "s3")]
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (uri, endpoint=None, region=None, path_style=false, access_key=None, secret_key=None, session_token=None))]
+    fn open_s3<'a>(
+        mut slf: PyRefMut<'a, Self>,
+        uri: &str,
+        endpoint: Option<&str>,
+        region: Option<&str>,
+        path_style: bool,
+        access_key: Option<&str>,
+        secret_key: Option<&str>,
+        session_token: Option<&str>,
+    ) -> Result<PyRefMut<'a, Self>> {
+        let mut config = S3Config::from_env();
+        config.with_path_style(path_style);
+        if let Some(endpoint) = endpoint {
+            config.with_endpoint(endpoint);
+        }
+        if let Some(region) = region {
+            config.with_region(region);
+        }
+        match (access_key, secret_key) {
+            (Some(access_key), Some(secret_key)) => {
+                config.with_credentials(access_key, secret_key, session_token);
+            }
+            (None, None) => {}
+            _ => bail!("access_key and secret_key must be given together"),
+        }
+        slf.inner.open_s3(uri, &config)?;
+        Ok(slf)
+    }
+
     fn by_size(mut slf: PyRefMut<'_, Self>, size: usize) -> PyRefMut<'_, Self> {
         slf.inner.by_size(size);
         slf
@@ -142,32 +224,412 @@ impl PyReader {
         slf
     }
 
+    fn shuffle_buffer_by_size(mut slf: PyRefMut<'_, Self>, size: usize) -> Result<PyRefMut<'_, Self>> {
+        slf.inner.shuffle_buffer_by_size(size)?;
+        Ok(slf)
+    }
+
+    fn shuffle_buffer_by_count(mut slf: PyRefMut<'_, Self>, count: usize) -> Result<PyRefMut<'_, Self>> {
+        slf.inner.shuffle_buffer_by_count(count)?;
+        Ok(slf)
+    }
+
     #[pyo3(text_signature = "(self, /, *, seed=None)")]
     fn with_shuffling(mut slf: PyRefMut<'_, Self>, seed: Option<u64>) -> PyRefMut<'_, Self> {
         slf.inner.with_shuffling(seed);
         slf
     }
 
+    fn set_epoch(mut slf: PyRefMut<'_, Self>, epoch: u64) -> PyRefMut<'_, Self> {
+        slf.inner.set_epoch(epoch);
+        slf
+    }
+
+    fn filter_prefix<'a>(mut slf: PyRefMut<'a, Self>, prefix: &str) -> PyRefMut<'a, Self> {
+        slf.inner.filter_prefix(prefix);
+        slf
+    }
+
+    fn filter_regex<'a>(mut slf: PyRefMut<'a, Self>, pattern: &str) -> Result<PyRefMut<'a, Self>> {
+        slf.inner.filter_regex(pattern)?;
+        Ok(slf)
+    }
+
+    fn filter_keys(mut slf: PyRefMut<'_, Self>, keys: Vec<String>) -> PyRefMut<'_, Self> {
+        slf.inner.filter_keys(keys);
+        slf
+    }
+
+    fn exclude_keys(mut slf: PyRefMut<'_, Self>, keys: Vec<String>) -> PyRefMut<'_, Self> {
+        slf.inner.exclude_keys(keys);
+        slf
+    }
+
+    #[pyo3(signature = (min_size=0, max_size=None))]
+    fn filter_size(mut slf: PyRefMut<'_, Self>, min_size: usize, max_size: Option<usize>) -> PyRefMut<'_, Self> {
+        slf.inner.filter_size(min_size..=max_size.unwrap_or(usize::MAX));
+        slf
+    }
+
+    fn with_sample_weights(mut slf: PyRefMut<'_, Self>, weights: Vec<f64>) -> Result<PyRefMut<'_, Self>> {
+        slf.inner.with_sample_weights(&weights)?;
+        Ok(slf)
+    }
+
+    fn with_stored_weights<'a>(mut slf: PyRefMut<'a, Self>, key: &str) -> Result<PyRefMut<'a, Self>> {
+        slf.inner.with_stored_weights(key)?;
+        Ok(slf)
+    }
+
+    #[pyo3(signature = (num_samples=None))]
+    fn with_replacement(mut slf: PyRefMut<'_, Self>, num_samples: Option<usize>) -> PyRefMut<'_, Self> {
+        slf.inner.with_replacement(num_samples);
+        slf
+    }
+
+    fn sample_by_block(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.inner.sample_by_block();
+        slf
+    }
+
+    #[pyo3(signature = (policy, retries=3))]
+    fn with_error_policy<'a>(mut slf: PyRefMut<'a, Self>, policy: &str, retries: u32) -> Result<PyRefMut<'a, Self>> {
+        slf.inner.with_error_policy(ErrorPolicy::from_name(policy, retries)?);
+        Ok(slf)
+    }
+
+    #[pyo3(signature = (len, align=1))]
+    fn with_random_window(mut slf: PyRefMut<'_, Self>, len: usize, align: usize) -> Result<PyRefMut<'_, Self>> {
+        slf.inner.with_random_window(len, align)?;
+        Ok(slf)
+    }
+
+    fn repeat(mut slf: PyRefMut<'_, Self>, epochs: u64) -> PyRefMut<'_, Self> {
+        slf.inner.repeat(epochs);
+        slf
+    }
+
+    fn repeat_forever(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.inner.repeat_forever();
+        slf
+    }
+
     fn with_sharding(mut slf: PyRefMut<'_, Self>, rank: u16, world_size: u16) -> Result<PyRefMut<'_, Self>> {
         slf.inner.with_sharding(rank, world_size)?;
         Ok(slf)
     }
 
+    fn with_nested_sharding(mut slf: PyRefMut<'_, Self>, rank: u16, world_size: u16) -> Result<PyRefMut<'_, Self>> {
+        slf.inner.with_nested_sharding(rank, world_size)?;
+        Ok(slf)
+    }
+
+    fn with_shard_mode<'a>(mut slf: PyRefMut<'a, Self>, mode: &str) -> Result<PyRefMut<'a, Self>> {
+        slf.inner.with_shard_mode(mode.parse()?);
+        Ok(slf)
+    }
+
+    fn balance_shards_by_size(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.inner.balance_shards_by_size();
+        slf
+    }
+
+    fn __len__(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn read_range(&self, py: Python, key: &str, start: usize, end: usize) -> Result<PyObject> {
+        Ok(PyBytes::new(py, &self.inner.read_range(key, start..end)?).into_py(py))
+    }
+
+    /// Durations are in seconds.
+    #[pyo3(signature = (max_retries=3, backoff=0.1, max_backoff=10.0, jitter=0.5, timeout=None, hedge_after=None))]
+    fn with_retries(
+        mut slf: PyRefMut<'_, Self>,
+        max_retries: u32,
+        backoff: f64,
+        max_backoff: f64,
+        jitter: f64,
+        timeout: Option<f64>,
+        hedge_after: Option<f64>,
+    ) -> Result<PyRefMut<'_, Self>> {
+        let mut policy = RetryPolicy::new(max_retries);
+        policy
+            .with_backoff(seconds(backoff)?, seconds(max_backoff)?)
+            .with_jitter(jitter);
+        if let Some(timeout) = timeout {
+            policy.with_timeout(seconds(timeout)?);
+        }
+        if let Some(after) = hedge_after {
+            policy.with_hedging(seconds(after)?);
+        }
+        slf.inner.with_retries(&policy)?;
+        Ok(slf)
+    }
+
+    #[cfg(any(feature = "http", feature = "gcs"))]
+    #[pyo3(signature = (dir, max_bytes, chunk_size=4 << 20))]
+    fn with_disk_cache<'a>(
+        mut slf: PyRefMut<'a, Self>,
+        dir: &str,
+        max_bytes: u64,
+        chunk_size: usize,
+    ) -> Result<PyRefMut<'a, Self>> {
+        slf.inner.with_disk_cache(dir, max_bytes, chunk_size)?;
+        Ok(slf)
+    }
+
+    fn with_block_cache(mut slf: PyRefMut<'_, Self>, capacity: usize) -> PyRefMut<'_, Self> {
+        slf.inner.with_block_cache(capacity);
+        slf
+    }
+
+    #[pyo3(signature = (max_bytes=8 << 20, max_gap=64 << 10))]
+    fn with_coalescing(mut slf: PyRefMut<'_, Self>, max_bytes: usize, max_gap: usize) -> PyRefMut<'_, Self> {
+        slf.inner.with_coalescing(max_bytes, max_gap);
+        slf
+    }
+
     fn with_buffering(mut slf: PyRefMut<'_, Self>, buffer_size: u32) -> Result<PyRefMut<'_, Self>> {
         slf.inner.with_buffering(buffer_size)?;
         Ok(slf)
     }
 
-    fn __iter__(&self) -> Result<EntryIter> {
+    fn __iter__(&self, py: Python) -> Result<EntryIter> {
         Ok(EntryIter {
-            iter: Arc::new(Mutex::new(self.inner.iter()?)),
+            iter: Arc::new(Mutex::new(self.worker_reader(py)?.iter()?)),
+        })
+    }
+
+    fn iter_from(&self, py: Python, state: &PyReaderState) -> Result<EntryIter> {
+        Ok(EntryIter {
+            iter: Arc::new(Mutex::new(self.worker_reader(py)?.iter_from(&state.inner)?)),
+        })
+    }
+
+    #[pyo3(signature = (batch_size, drop_last=false, state=None))]
+    fn iter_batches(
+        &self,
+        py: Python,
+        batch_size: usize,
+        drop_last: bool,
+        state: Option<&PyReaderState>,
+    ) -> Result<PyBatchIter> {
+        let reader = self.worker_reader(py)?;
+        let iter = match state {
+            Some(state) => reader.iter_from(&state.inner)?,
+            None => reader.iter()?,
+        };
+        Ok(PyBatchIter {
+            iter: iter.batched(batch_size, drop_last)?,
         })
     }
+
+    #[pyo3(signature = (batch_size, boundaries=None, max_waste=None, drop_last=false, state=None))]
+    fn iter_buckets(
+        &self,
+        py: Python,
+        batch_size: usize,
+        boundaries: Option<Vec<usize>>,
+        max_waste: Option<f64>,
+        drop_last: bool,
+        state: Option<&PyBucketState>,
+    ) -> Result<PyBucketIter> {
+        let bucketing = match (boundaries, max_waste) {
+            (Some(boundaries), None) => Bucketing::Boundaries(boundaries),
+            (None, Some(max_waste)) => Bucketing::MaxWaste(max_waste),
+            _ => bail!("Exactly one of boundaries and max_waste must be given"),
+        };
+        let reader = self.worker_reader(py)?;
+        let iter = match state {
+            Some(state) => reader.iter_buckets_from(&state.inner, &bucketing, batch_size, drop_last)?,
+            None => reader.iter_buckets(&bucketing, batch_size, drop_last)?,
+        };
+        Ok(PyBucketIter { iter })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (seq_len, dtype="uint16", separator=None, segments=false, drop_last=true, state=None))]
+    fn iter_packed(
+        &self,
+        py: Python,
+        seq_len: usize,
+        dtype: &str,
+        separator: Option<u32>,
+        segments: bool,
+        drop_last: bool,
+        state: Option<&PyPackState>,
+    ) -> Result<PyPackIter> {
+        let mut packing = Packing::new(dtype.parse::<TokenWidth>()?, seq_len);
+        if let Some(separator) = separator {
+            packing.with_separator(separator);
+        }
+        if segments {
+            packing.with_segments();
+        }
+        if !drop_last {
+            packing.keep_last();
+        }
+        let reader = self.worker_reader(py)?;
+        let iter = match state {
+            Some(state) => reader.iter_packed_from(&state.inner, &packing)?,
+            None => reader.iter_packed(&packing)?,
+        };
+        Ok(PyPackIter { iter })
+    }
+}
+
+impl PyReader {
+    /// The reader, additionally sharded over the workers of the torch DataLoader iterating it, if any.
+    fn worker_reader(&self, py: Python) -> Result<Reader> {
+        let mut reader = self.inner.clone();
+        if let Some((worker, num_workers)) = torch_worker_info(py)? {
+            reader.with_worker_sharding(worker, num_workers)?;
+        }
+        Ok(reader)
+    }
+}
+
+fn torch_worker_info(py: Python) -> PyResult<Option<(u16, u16)>> {
+    let data = match py.import("torch.utils.data") {
+        Ok(data) => data,
+        Err(err) if err.is_instance_of::<PyImportError>(py) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let info = data.getattr("get_worker_info")?.call0()?;
+    if info.is_none() {
+        return Ok(None);
+    }
+    Ok(Some((
+        info.getattr("id")?.extract()?,
+        info.getattr("num_workers")?.extract()?,
+    )))
+}
+
+#[derive(FromPyObject)]
+enum DatasetIndex {
+    Index(isize),
+    Key(String),
+}
+
+fn dataset_err(err: Report) -> PyErr {
+    match err.downcast_ref::<DatasetError>() {
+        Some(DatasetError::IndexOutOfRange(..)) => PyIndexError::new_err(err.to_string()),
+        Some(DatasetError::KeyNotFound(..)) => PyKeyError::new_err(err.to_string()),
+        None => err.into(),
+    }
+}
+
+fn sample_into_py(py: Python, (key, value): Sample) -> PyObject {
+    let key = key.to_object(py);
+    let value = PyBytes::new(py, &value).into_py(py);
+    PyTuple::new(py, [key, value]).into_py(py)
+}
+
+#[pyclass(name = "Dataset", module = "rand_archive")]
+struct PyDataset {
+    path: String,
+    cache_size: Option<usize>,
+    pid: u32,
+    inner: Option<Dataset>,
+}
+
+impl PyDataset {
+    /// Opens the archive on first use in each process, so forked or unpickled workers never share a file handle.
+    fn inner(&mut self) -> Result<&mut Dataset> {
+        let pid = process::id();
+        if self.inner.is_none() || self.pid != pid {
+            let mut dataset = Dataset::open_file(&self.path)?;
+            if let Some(capacity) = self.cache_size {
+                dataset.with_block_cache(capacity);
+            }
+            self.inner = Some(dataset);
+            self.pid = pid;
+        }
+        Ok(self.inner.as_mut().unwrap())
+    }
+}
+
+#[pymethods]
+impl PyDataset {
+    #[new]
+    #[pyo3(signature = (path, cache_size=None))]
+    fn new(path: String, cache_size: Option<usize>) -> Self {
+        Self {
+            path,
+            cache_size,
+            pid: 0,
+            inner: None,
+        }
+    }
+
+    fn __len__(&mut self) -> Result<usize> {
+        Ok(self.inner()?.len())
+    }
+
+    fn __contains__(&mut self, key: &str) -> Result<bool> {
+        Ok(self.inner()?.header().entries().contains_key(key))
+    }
+
+    fn __getitem__(&mut self, py: Python, index: DatasetIndex) -> PyResult<PyObject> {
+        let inner = self.inner()?;
+        let sample = match index {
+            DatasetIndex::Index(i) => {
+                let len = inner.len();
+                let i = if i < 0 { i + len as isize } else { i };
+                if i < 0 {
+                    return Err(PyIndexError::new_err(format!(
+                        "Index {i} out of range for dataset of length {len}"
+                    )));
+                }
+                py.allow_threads(|| inner.get_index(i as usize))
+            }
+            DatasetIndex::Key(key) => py.allow_threads(|| inner.get_key(&key)),
+        };
+        Ok(sample_into_py(py, sample.map_err(dataset_err)?))
+    }
+
+    fn __reduce__(slf: &PyCell<Self>, py: Python) -> (PyObject, (String, Option<usize>)) {
+        let dataset = slf.borrow();
+        (slf.get_type().into_py(py), (dataset.path.clone(), dataset.cache_size))
+    }
+}
+
+#[pyclass(name = "ReaderState", module = "rand_archive")]
+struct PyReaderState {
+    inner: ReaderState,
+}
+
+#[pymethods]
+impl PyReaderState {
+    #[new]
+    fn new(state: &[u8]) -> Result<Self> {
+        Ok(Self {
+            inner: ReaderState::from_bytes(state)?,
+        })
+    }
+
+    #[getter]
+    fn epoch(&self) -> u64 {
+        self.inner.epoch()
+    }
+
+    fn to_bytes(&self, py: Python) -> Result<PyObject> {
+        Ok(PyBytes::new(py, &self.inner.to_bytes()?).into_py(py))
+    }
+
+    fn __reduce__(slf: &PyCell<Self>, py: Python) -> Result<(PyObject, (PyObject,))> {
+        Ok((slf.get_type().into_py(py), (slf.borrow().to_bytes(py)?,)))
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.inner)
+    }
 }
 
 #[pyclass]
 struct EntryIter {
-    iter: Arc<Mutex<dyn Iterator<Item = (String, Bytes)>>>,
+    iter: Arc<Mutex<SampleIter>>,
 }
 
 #[pymethods]
@@ -176,21 +638,281 @@ impl EntryIter {
         slf
     }
 
-    fn __next__(mut slf: PyRefMut<'_, Self>) -> Option<PyObject> {
-        match slf.iter.lock().unwrap().next() {
-            Some((key, value)) => Python::with_gil(|gil| {
-                let key = key.to_object(gil);
-                let value = PyBytes::new(gil, &value).into_py(gil);
-                let tuple = PyTuple::new(gil, [key, value]);
-                Some(tuple.into_py(gil))
-            }),
-            None => None,
+    #[getter]
+    fn epoch(&self) -> u64 {
+        self.iter.lock().unwrap().epoch()
+    }
+
+    fn state(&self) -> PyReaderState {
+        PyReaderState {
+            inner: self.iter.lock().unwrap().state(),
+        }
+    }
+
+    fn __next__(slf: PyRefMut<'_, Self>) -> PyResult<Option<PyObject>> {
+        let item = slf.iter.lock().unwrap().next();
+        item.map(|sample| Python::with_gil(|gil| Ok(sample_into_py(gil, sample.map_err(into_py_err)?))))
+            .transpose()
+    }
+}
+
+fn seconds(value: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(value).wrap_err_with(|| format!("Invalid duration of {value} seconds"))
+}
+
+/// Errors reading data become an `OSError`, any other error a `RuntimeError`, both with the whole error chain.
+fn into_py_err(err: Report) -> PyErr {
+    let message = format!("{err:#}");
+    let io = err.downcast_ref::<ReadError>().is_some() || err.chain().any(|cause| cause.is::<std::io::Error>());
+    match io {
+        true => PyIOError::new_err(message),
+        false => PyRuntimeError::new_err(message),
+    }
+}
+
+fn batch_into_py(py: Python, batch: Batch) -> PyObject {
+    let keys = batch.keys().to_object(py);
+    let data = PyBytes::new(py, batch.data()).into_py(py);
+    let offsets = batch.offsets().to_object(py);
+    PyTuple::new(py, [keys, data, offsets]).into_py(py)
+}
+
+#[pyclass(name = "BatchIter")]
+struct PyBatchIter {
+    iter: BatchIter,
+}
+
+#[pymethods]
+impl PyBatchIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    #[getter]
+    fn epoch(&self) -> u64 {
+        self.iter.epoch()
+    }
+
+    fn state(&self) -> PyReaderState {
+        PyReaderState {
+            inner: self.iter.state(),
+        }
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python) -> PyResult<Option<PyObject>> {
+        slf.iter
+            .next()
+            .map(|batch| Ok(batch_into_py(py, batch.map_err(into_py_err)?)))
+            .transpose()
+    }
+}
+
+#[pyclass(name = "BucketState", module = "rand_archive")]
+struct PyBucketState {
+    inner: BucketState,
+}
+
+#[pymethods]
+impl PyBucketState {
+    #[new]
+    fn new(state: &[u8]) -> Result<Self> {
+        Ok(Self {
+            inner: BucketState::from_bytes(state)?,
+        })
+    }
+
+    fn to_bytes(&self, py: Python) -> Result<PyObject> {
+        Ok(PyBytes::new(py, &self.inner.to_bytes()?).into_py(py))
+    }
+
+    fn __reduce__(slf: &PyCell<Self>, py: Python) -> Result<(PyObject, (PyObject,))> {
+        Ok((slf.get_type().into_py(py), (slf.borrow().to_bytes(py)?,)))
+    }
+}
+
+#[pyclass(name = "BucketIter")]
+struct PyBucketIter {
+    iter: BucketIter,
+}
+
+#[pymethods]
+impl PyBucketIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    #[getter]
+    fn epoch(&self) -> u64 {
+        self.iter.epoch()
+    }
+
+    fn state(&self) -> PyBucketState {
+        PyBucketState {
+            inner: self.iter.state(),
+        }
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python) -> PyResult<Option<PyObject>> {
+        slf.iter
+            .next()
+            .map(|batch| Ok(batch_into_py(py, batch.map_err(into_py_err)?)))
+            .transpose()
+    }
+}
+
+/// A sequence as a (tokens, segments) tuple, segments is None unless requested.
+fn sequence_into_py(py: Python, sequence: Sequence) -> PyObject {
+    let tokens = PyBytes::new(py, sequence.data()).into_py(py);
+    let segments = sequence.segments().to_object(py);
+    PyTuple::new(py, [tokens, segments]).into_py(py)
+}
+
+#[pyclass(name = "PackState", module = "rand_archive")]
+struct PyPackState {
+    inner: PackState,
+}
+
+#[pymethods]
+impl PyPackState {
+    #[new]
+    fn new(state: &[u8]) -> Result<Self> {
+        Ok(Self {
+            inner: PackState::from_bytes(state)?,
+        })
+    }
+
+    fn to_bytes(&self, py: Python) -> Result<PyObject> {
+        Ok(PyBytes::new(py, &self.inner.to_bytes()?).into_py(py))
+    }
+
+    fn __reduce__(slf: &PyCell<Self>, py: Python) -> Result<(PyObject, (PyObject,))> {
+        Ok((slf.get_type().into_py(py), (slf.borrow().to_bytes(py)?,)))
+    }
+}
+
+#[pyclass(name = "PackIter")]
+struct PyPackIter {
+    iter: PackIter,
+}
+
+#[pymethods]
+impl PyPackIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    #[getter]
+    fn epoch(&self) -> u64 {
+        self.iter.epoch()
+    }
+
+    fn state(&self) -> PyPackState {
+        PyPackState {
+            inner: self.iter.state(),
+        }
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python) -> PyResult<Option<PyObject>> {
+        slf.iter
+            .next()
+            .map(|sequence| Ok(sequence_into_py(py, sequence.map_err(into_py_err)?)))
+            .transpose()
+    }
+}
+
+#[pyclass[name = "Mixer"]]
+struct PyMixer {
+    inner: Mixer,
+}
+
+#[pymethods]
+impl PyMixer {
+    #[new]
+    #[pyo3(signature = (sources, seed=0, mode="exhaust"))]
+    fn new(sources: Vec<(PyRef<PyReader>, f64)>, seed: u64, mode: &str) -> Result<Self> {
+        let mut inner = Mixer::new(seed);
+        inner.with_mode(mode.parse()?);
+        for (reader, weight) in sources {
+            inner.add(reader.inner.clone(), weight)?;
+        }
+        Ok(Self { inner })
+    }
+
+    fn with_sharding(mut slf: PyRefMut<'_, Self>, rank: u16, world_size: u16) -> Result<PyRefMut<'_, Self>> {
+        slf.inner.with_sharding(rank, world_size)?;
+        Ok(slf)
+    }
+
+    fn __iter__(&self, py: Python) -> Result<PyMixIter> {
+        Ok(PyMixIter {
+            iter: self.worker_mixer(py)?.iter()?,
+        })
+    }
+
+    fn iter_from(&self, py: Python, state: &PyMixState) -> Result<PyMixIter> {
+        Ok(PyMixIter {
+            iter: self.worker_mixer(py)?.iter_from(&state.inner)?,
+        })
+    }
+}
+
+impl PyMixer {
+    fn worker_mixer(&self, py: Python) -> Result<Mixer> {
+        let mut mixer = self.inner.clone();
+        if let Some((worker, num_workers)) = torch_worker_info(py)? {
+            mixer.with_worker_sharding(worker, num_workers)?;
         }
+        Ok(mixer)
     }
 }
 
-unsafe impl Send for EntryIter {}
-unsafe impl Sync for EntryIter {}
+#[pyclass(name = "MixState", module = "rand_archive")]
+struct PyMixState {
+    inner: MixState,
+}
+
+#[pymethods]
+impl PyMixState {
+    #[new]
+    fn new(state: &[u8]) -> Result<Self> {
+        Ok(Self {
+            inner: MixState::from_bytes(state)?,
+        })
+    }
+
+    fn to_bytes(&self, py: Python) -> Result<PyObject> {
+        Ok(PyBytes::new(py, &self.inner.to_bytes()?).into_py(py))
+    }
+
+    fn __reduce__(slf: &PyCell<Self>, py: Python) -> Result<(PyObject, (PyObject,))> {
+        Ok((slf.get_type().into_py(py), (slf.borrow().to_bytes(py)?,)))
+    }
+}
+
+#[pyclass(name = "MixIter")]
+struct PyMixIter {
+    iter: MixIter,
+}
+
+#[pymethods]
+impl PyMixIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn state(&self) -> PyMixState {
+        PyMixState {
+            inner: self.iter.state(),
+        }
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python) -> PyResult<Option<PyObject>> {
+        slf.iter
+            .next()
+            .map(|sample| Ok(sample_into_py(py, sample.map_err(into_py_err)?)))
+            .transpose()
+    }
+}
 
 #[pyfunction]
 fn __setup() -> Result<()> {
@@ -202,7 +924,17 @@ fn rand_archive(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyHeader>()?;
     m.add_class::<PyWriter>()?;
     m.add_class::<PyReader>()?;
+    m.add_class::<PyDataset>()?;
+    m.add_class::<PyReaderState>()?;
     m.add_class::<EntryIter>()?;
+    m.add_class::<PyBatchIter>()?;
+    m.add_class::<PyBucketState>()?;
+    m.add_class::<PyBucketIter>()?;
+    m.add_class::<PyPackState>()?;
+    m.add_class::<PyPackIter>()?;
+    m.add_class::<PyMixer>()?;
+    m.add_class::<PyMixState>()?;
+    m.add_class::<PyMixIter>()?;
     m.add_wrapped(wrap_pyfunction!(__setup))?;
     Ok(())
 }
@@ -0,0 +1,213 @@
+use std::mem;
+
+use bytes::{Bytes, BytesMut};
+use color_eyre::eyre::{ensure, Result};
+use thiserror::Error;
+
+use crate::reader::iter::{ReaderState, SampleIter};
+use crate::reader::Sample;
+
+#[derive(Error, Debug)]
+pub enum BatchError {
+    #[error("Batch size must be greater than 0")]
+    InvalidBatchSize,
+    #[error("Bucket boundaries must be strictly ascending")]
+    InvalidBoundaries,
+    #[error("Padding waste must be in [0, 1), got: {0}")]
+    InvalidWaste(f64),
+    #[error("Bucket state was saved with {0} buckets, expected {1}")]
+    BucketMismatch(usize, usize),
+}
+
+/// Samples packed into one contiguous buffer, the value of sample `i` is `data[offsets[i]..offsets[i + 1]]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    keys: Vec<String>,
+    data: Bytes,
+    offsets: Vec<usize>,
+}
+
+impl Batch {
+    pub fn from_samples(samples: Vec<Sample>) -> Self {
+        let mut data = BytesMut::with_capacity(samples.iter().map(|(_, value)| value.len()).sum());
+        let mut offsets = Vec::with_capacity(samples.len() + 1);
+        offsets.push(0);
+        let keys = samples
+            .into_iter()
+            .map(|(key, value)| {
+                data.extend_from_slice(&value);
+                offsets.push(data.len());
+                key
+            })
+            .collect();
+        Self {
+            keys,
+            data: data.freeze(),
+            offsets,
+        }
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Sample> {
+        let key = self.keys.get(index)?;
+        Some((
+            key.clone(),
+            self.data.slice(self.offsets[index]..self.offsets[index + 1]),
+        ))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Sample> + '_ {
+        (0..self.len()).map(|index| self.get(index).unwrap())
+    }
+}
+
+/// Groups the samples of a [`SampleIter`] into batches of a fixed size, only the last one may be smaller.
+///
+/// Samples taken before an error are kept for the batch following it.
+pub struct BatchIter {
+    samples: SampleIter,
+    batch_size: usize,
+    drop_last: bool,
+    pending: Vec<Sample>,
+}
+
+impl BatchIter {
+    pub(crate) fn new(samples: SampleIter, batch_size: usize, drop_last: bool) -> Result<Self> {
+        ensure!(batch_size > 0, BatchError::InvalidBatchSize);
+        Ok(Self {
+            samples,
+            batch_size,
+            drop_last,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.samples.epoch()
+    }
+
+    /// Position after the last batch taken, resuming from it with the same batch size yields the following batches.
+    pub fn state(&self) -> ReaderState {
+        self.samples.state()
+    }
+}
+
+impl Iterator for BatchIter {
+    type Item = Result<Batch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.len() < self.batch_size {
+            match self.samples.next() {
+                Some(Ok(sample)) => self.pending.push(sample),
+                Some(Err(err)) => return Some(Err(err)),
+                None => break,
+            }
+        }
+        let samples = mem::take(&mut self.pending);
+        if samples.is_empty() || (self.drop_last && samples.len() < self.batch_size) {
+            return None;
+        }
+        Some(Ok(Batch::from_samples(samples)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::Reader;
+    use crate::test_setup::*;
+
+    #[test]
+    fn test_batches() {
+        setup();
+        let file = new_dummy_path(30, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_shuffling(Some(7));
+        let samples: Vec<Sample> = reader.iter().unwrap().map(Result::unwrap).collect();
+
+        let batches: Vec<Batch> = reader
+            .iter()
+            .unwrap()
+            .batched(8, false)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(),
+            vec![8, 8, 8, 6]
+        );
+        assert_eq!(batches[3].offsets(), &[0, 10, 20, 30, 40, 50, 60]);
+        let unbatched: Vec<Sample> = batches
+            .iter()
+            .flat_map(|batch| batch.iter().collect::<Vec<_>>())
+            .collect();
+        assert_eq!(unbatched, samples);
+
+        let dropped: Vec<Batch> = reader
+            .iter()
+            .unwrap()
+            .batched(8, true)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(dropped, batches[..3]);
+        assert!(reader.iter().unwrap().batched(0, true).is_err());
+    }
+
+    #[test]
+    fn test_batches_resume() {
+        setup();
+        let file = new_dummy_path(30, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_shuffling(Some(7))
+            .shuffle_buffer_by_count(6)
+            .unwrap();
+        let expected: Vec<Batch> = reader
+            .iter()
+            .unwrap()
+            .batched(7, false)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+
+        let mut batches = reader.iter().unwrap().batched(7, false).unwrap();
+        let mut resumed: Vec<Batch> = batches.by_ref().take(2).map(Result::unwrap).collect();
+        let state = batches.state();
+        resumed.extend(
+            reader
+                .iter_from(&state)
+                .unwrap()
+                .batched(7, false)
+                .unwrap()
+                .map(Result::unwrap),
+        );
+        assert_eq!(resumed, expected);
+    }
+}
@@ -1,56 +1,197 @@
-use std::cell::RefCell;
 use std::ops::Range;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use bytes::Bytes;
-use color_eyre::eyre::{eyre, Result};
+use color_eyre::eyre::{ensure, eyre, Result};
+use futures::executor::block_on;
+use futures::future::try_join_all;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-use crate::reader::datasource::{AsyncDataSource, SyncDataSource};
-use crate::reader::readers::RcHeader;
+use crate::reader::datasource::{AsyncDataSource, DataSource, SyncDataSource};
+use crate::reader::policy::ReadError;
+use crate::reader::readers::ArcHeader;
 use crate::reader::Sample;
 
+/// Archive and position in its header of a sample.
+pub(crate) type SampleId = (usize, usize);
+
+/// Id of a sample and the epoch it was yielded in, which picks the window cropped out of it.
+pub(crate) type YieldedId = (u64, SampleId);
+
+/// Crop of at most `len` bytes out of every sample, starting at a random multiple of `align`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Window {
+    pub(crate) len: usize,
+    pub(crate) align: usize,
+}
+
+impl Window {
+    /// Byte range of the crop of the sample at `range`, the same for a given seed and sample.
+    fn crop(&self, range: Range<usize>, seed: u64, (archive, idx): SampleId) -> Range<usize> {
+        if range.len() <= self.len {
+            return range;
+        }
+        let id = ((archive as u64) << 40 | idx as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        let mut rng = StdRng::seed_from_u64(seed ^ id);
+        let start = range.start + rng.gen_range(0..=(range.len() - self.len) / self.align) * self.align;
+        start..start + self.len
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct Block {
-    header: RcHeader,
+    archive: usize,
+    header: ArcHeader,
     range: Range<usize>,
+    window: Option<(Window, u64)>,
     buffer: Option<Bytes>,
 }
 
 impl Block {
-    pub(crate) fn from_range(header: RcHeader, range: Range<usize>) -> Self {
+    pub(crate) fn from_range(archive: usize, header: ArcHeader, range: Range<usize>) -> Self {
         let header = header.clone();
         Self {
+            archive,
             header,
             range,
+            window: None,
             buffer: None,
         }
     }
 
+    /// Only reads the crop `window` picks with `seed` out of every sample.
+    pub(crate) fn with_window(mut self, window: Window, seed: u64) -> Self {
+        self.window = Some((window, seed));
+        self
+    }
+
+    pub(crate) fn archive(&self) -> usize {
+        self.archive
+    }
+
+    pub(crate) fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    pub(crate) fn ids(&self) -> impl Iterator<Item = SampleId> {
+        let archive = self.archive;
+        self.range.clone().map(move |idx| (archive, idx))
+    }
+
     pub(crate) fn len(&self) -> usize {
         self.range.end - self.range.start
     }
 
-    pub(crate) fn read(mut self, data_source: Rc<RefCell<dyn SyncDataSource>>) -> Result<Self> {
-        let data_source = &mut *data_source.borrow_mut();
-        let byte_range = self.header.byte_range_of(&self.range).ok_or(eyre!("Invalid range"))?;
-        self.buffer = Some(data_source.get_range(byte_range)?);
+    pub(crate) fn byte_size(&self) -> usize {
+        self.header.byte_range_of(&self.range).map_or(0, |range| range.len())
+    }
+
+    /// Bytes the block is read from in one piece, `None` if it only reads windows of its samples.
+    pub(crate) fn contiguous_range(&self) -> Option<Range<usize>> {
+        match self.window {
+            Some(_) => None,
+            None => self.header.byte_range_of(&self.range),
+        }
+    }
+
+    /// The block as if it was read, `buffer` holding the bytes of its samples.
+    pub(crate) fn with_buffer(mut self, buffer: Bytes) -> Self {
+        self.buffer = Some(buffer);
+        self
+    }
+
+    /// One unread block per sample of this block.
+    pub(crate) fn split(&self) -> impl Iterator<Item = Self> + '_ {
+        self.range
+            .clone()
+            .map(|idx| Self::from_range(self.archive, self.header.clone(), idx..idx + 1))
+    }
+
+    /// The first `len` samples of the block, unread.
+    pub(crate) fn take(&self, len: usize) -> Self {
+        let start = self.range.start;
+        Self::from_range(self.archive, self.header.clone(), start..start + len.min(self.len()))
+    }
+
+    /// Byte ranges to read, the whole block at once unless only a window of every sample is wanted.
+    fn byte_ranges(&self) -> Result<Vec<Range<usize>>> {
+        let Some((window, seed)) = self.window else {
+            return Ok(vec![self
+                .header
+                .byte_range_of(&self.range)
+                .ok_or(eyre!("Invalid range"))?]);
+        };
+        self.ids()
+            .map(|(archive, idx)| {
+                let range = self
+                    .header
+                    .byte_range_of(&(idx..idx + 1))
+                    .ok_or(eyre!("Invalid range"))?;
+                Ok(window.crop(range, seed, (archive, idx)))
+            })
+            .collect()
+    }
+
+    fn concat(chunks: Vec<Bytes>) -> Bytes {
+        match chunks.len() {
+            1 => chunks.into_iter().next().unwrap(),
+            _ => chunks.concat().into(),
+        }
+    }
+
+    pub(crate) fn read(mut self, data_source: Arc<dyn SyncDataSource>) -> Result<Self> {
+        let chunks = self
+            .byte_ranges()?
+            .into_iter()
+            .map(|range| data_source.get_range(range))
+            .collect::<Result<Vec<_>>>()?;
+        self.buffer = Some(Self::concat(chunks));
         Ok(self)
     }
 
-    pub(crate) async fn read_async(mut self, data_source: Rc<dyn AsyncDataSource>) -> Result<Self> {
-        let byte_range = self.header.byte_range_of(&self.range).ok_or(eyre!("Invalid range"))?;
-        self.buffer = Some(data_source.get_range_async(byte_range).await?);
+    pub(crate) async fn read_async(mut self, data_source: Arc<dyn AsyncDataSource>) -> Result<Self> {
+        let ranges = self.byte_ranges()?;
+        let chunks = try_join_all(ranges.into_iter().map(|range| data_source.get_range_async(range))).await?;
+        self.buffer = Some(Self::concat(chunks));
         Ok(self)
     }
 
+    pub(crate) async fn read_source(self, data_source: DataSource) -> Result<Self> {
+        match data_source {
+            DataSource::Sync(inner) => self.read(inner),
+            DataSource::Async(inner) => self.read_async(inner).await,
+        }
+    }
+
+    pub(crate) fn read_from(self, data_source: &DataSource) -> Result<Self> {
+        block_on(self.read_source(data_source.clone()))
+    }
+
     pub(crate) fn to_vec(&self) -> Result<Vec<Sample>> {
         let mut data = self.buffer.clone().ok_or(eyre!("Unread block"))?;
-        Ok(self
-            .header
+        self.header
             .get_range(self.range.clone())
             .ok_or(eyre!("Invalid range"))?
             .iter()
-            .map(|(key, entry)| (key.to_owned(), data.split_to(entry.length())))
-            .collect())
+            .map(|(key, entry)| {
+                let len = self
+                    .window
+                    .map_or(entry.length(), |(window, _)| window.len.min(entry.length()));
+                ensure!(data.len() >= len, eyre!("Sample {key} is cut short"));
+                Ok((key.to_owned(), data.split_to(len)))
+            })
+            .collect()
+    }
+
+    /// Error naming the samples and bytes of this block.
+    pub(crate) fn read_error(&self) -> ReadError {
+        let key = |idx| self.header.get_index(idx).map_or(String::new(), |(key, _)| key.clone());
+        ReadError::Block {
+            archive: self.archive,
+            first: key(self.range.start),
+            last: key(self.range.end.saturating_sub(1)),
+            bytes: self.header.byte_range_of(&self.range).unwrap_or_default(),
+        }
     }
 }
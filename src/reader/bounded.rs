@@ -1,25 +1,26 @@
-use std::vec::IntoIter;
-
 use futures::{Future, stream, StreamExt};
-use futures::stream::{BufferUnordered, Iter};
+use futures::stream::{Buffered, Iter};
 use tokio::runtime::{Builder, Runtime};
 
-pub(crate) struct BoundedIter<F>
+pub(crate) struct BoundedIter<I>
 where
-    F: Future,
+    I: Iterator,
+    I::Item: Future,
 {
-    iter: BufferUnordered<Iter<IntoIter<F>>>,
+    iter: Buffered<Iter<I>>,
     rt: Runtime
 }
 
-impl<F> BoundedIter<F>
+impl<I> BoundedIter<I>
 where
-    F: Future,
+    I: Iterator,
+    I::Item: Future,
 {
-    pub(crate) fn from_vec(vec: Vec<F>, limit: usize) -> Self {
-        let stream = stream::iter(vec);
+    /// Polls up to `limit` futures of `iter` at once, pulling new ones lazily so `iter` may be endless.
+    pub(crate) fn new(iter: I, limit: usize) -> Self {
+        let stream = stream::iter(iter);
         BoundedIter {
-            iter: stream.buffer_unordered(limit),
+            iter: stream.buffered(limit),
             rt: Builder::new_current_thread()
                 .enable_all()
                 .build()
@@ -28,11 +29,12 @@ where
     }
 }
 
-impl<F> Iterator for BoundedIter<F>
+impl<I> Iterator for BoundedIter<I>
 where
-    F: Future,
+    I: Iterator,
+    I::Item: Future,
 {
-    type Item = F::Output;
+    type Item = <I::Item as Future>::Output;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.rt.block_on(&mut self.iter.next())
@@ -0,0 +1,253 @@
+use std::mem;
+
+use bincode::Options;
+use color_eyre::eyre::{ensure, eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::reader::batch::{Batch, BatchError};
+use crate::reader::block::YieldedId;
+use crate::reader::iter::{ReaderState, SampleIter};
+use crate::reader::Sample;
+
+/// How samples are split into buckets of similar byte length.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bucketing {
+    /// Ascending lengths separating the buckets, bucket `i` holds samples shorter than `boundaries[i]`.
+    Boundaries(Vec<usize>),
+    /// Boundaries picked from the sample lengths, so that a sample is at most this fraction shorter than any other in
+    /// its bucket.
+    MaxWaste(f64),
+}
+
+impl Bucketing {
+    /// Bucket boundaries for samples of `lengths`.
+    pub(crate) fn boundaries(&self, lengths: &[usize]) -> Result<Vec<usize>> {
+        match self {
+            Bucketing::Boundaries(boundaries) => {
+                ensure!(
+                    boundaries.windows(2).all(|pair| pair[0] < pair[1]),
+                    BatchError::InvalidBoundaries
+                );
+                Ok(boundaries.clone())
+            }
+            Bucketing::MaxWaste(waste) => {
+                ensure!((0.0..1.0).contains(waste), BatchError::InvalidWaste(*waste));
+                let mut lengths = lengths.to_vec();
+                lengths.sort_unstable();
+                lengths.dedup();
+                let mut boundaries = Vec::new();
+                let mut lowest = lengths.first().copied().unwrap_or(0);
+                for length in lengths {
+                    if (length - lowest) as f64 > waste * length as f64 {
+                        boundaries.push(length);
+                        lowest = length;
+                    }
+                }
+                Ok(boundaries)
+            }
+        }
+    }
+}
+
+/// Position of a [`BucketIter`], the reader position and the samples waiting in every bucket.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BucketState {
+    pub(crate) reader: ReaderState,
+    pub(crate) buckets: Vec<Vec<YieldedId>>,
+}
+
+impl BucketState {
+    fn get_options() -> impl Options {
+        bincode::DefaultOptions::new().with_varint_encoding().with_big_endian()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Self::get_options()
+            .serialize(self)
+            .map_err(|e| eyre!(e))
+            .wrap_err("Failed to write bucket state")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::get_options()
+            .deserialize(bytes)
+            .map_err(|e| eyre!(e))
+            .wrap_err("Failed to read bucket state")
+    }
+}
+
+/// Batches samples of similar length together, a batch is yielded as soon as its bucket is full.
+///
+/// Samples still waiting once the reader is exhausted are yielded bucket by bucket as smaller batches.
+pub struct BucketIter {
+    samples: SampleIter,
+    boundaries: Vec<usize>,
+    buckets: Vec<Vec<(YieldedId, Sample)>>,
+    batch_size: usize,
+    drop_last: bool,
+}
+
+impl BucketIter {
+    /// `buckets` are the samples restored from a [`BucketState`], or empty.
+    pub(crate) fn new(
+        samples: SampleIter,
+        boundaries: Vec<usize>,
+        buckets: Vec<Vec<(YieldedId, Sample)>>,
+        batch_size: usize,
+        drop_last: bool,
+    ) -> Result<Self> {
+        ensure!(batch_size > 0, BatchError::InvalidBatchSize);
+        let count = boundaries.len() + 1;
+        let buckets = match buckets.is_empty() {
+            true => vec![Vec::new(); count],
+            false => buckets,
+        };
+        ensure!(buckets.len() == count, BatchError::BucketMismatch(buckets.len(), count));
+        Ok(Self {
+            samples,
+            boundaries,
+            buckets,
+            batch_size,
+            drop_last,
+        })
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.samples.epoch()
+    }
+
+    pub fn state(&self) -> BucketState {
+        BucketState {
+            reader: self.samples.state(),
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.iter().map(|(id, _)| *id).collect())
+                .collect(),
+        }
+    }
+
+    fn take(bucket: &mut Vec<(YieldedId, Sample)>) -> Batch {
+        Batch::from_samples(mem::take(bucket).into_iter().map(|(_, sample)| sample).collect())
+    }
+}
+
+impl Iterator for BucketIter {
+    type Item = Result<Batch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(item) = self.samples.next_with_id() {
+            let (id, sample) = match item {
+                Ok(item) => item,
+                Err(err) => return Some(Err(err)),
+            };
+            let bucket = self.boundaries.partition_point(|boundary| *boundary <= sample.1.len());
+            self.buckets[bucket].push((id, sample));
+            if self.buckets[bucket].len() >= self.batch_size {
+                return Some(Ok(Self::take(&mut self.buckets[bucket])));
+            }
+        }
+        if self.drop_last {
+            self.buckets.iter_mut().for_each(Vec::clear);
+            return None;
+        }
+        self.buckets
+            .iter_mut()
+            .find(|bucket| !bucket.is_empty())
+            .map(|bucket| Ok(Self::take(bucket)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Writer;
+    use crate::reader::Reader;
+    use crate::test_setup::*;
+
+    /// Archive of 60 samples, sample `i` is `i % 6 * 10 + 10` bytes long.
+    fn varied_file() -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = Writer::new(file.as_file().try_clone().unwrap(), 1024, 10 * 1024).unwrap();
+        for i in 0..60 {
+            writer
+                .write(&format!("{i:06}"), &generate_random_value(i % 6 * 10 + 10))
+                .unwrap();
+        }
+        writer.close().unwrap();
+        file
+    }
+
+    fn reader(file: &tempfile::NamedTempFile) -> Reader {
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_shuffling(Some(7));
+        reader
+    }
+
+    #[test]
+    fn test_boundaries() {
+        setup();
+        let lengths = [10, 10, 11, 12, 20, 21, 100];
+        assert_eq!(Bucketing::MaxWaste(0.2).boundaries(&lengths).unwrap(), vec![20, 100]);
+        assert_eq!(Bucketing::MaxWaste(0.0).boundaries(&lengths).unwrap().len(), 5);
+        assert!(Bucketing::MaxWaste(1.0).boundaries(&lengths).is_err());
+        assert!(Bucketing::Boundaries(vec![20, 10]).boundaries(&lengths).is_err());
+    }
+
+    #[test]
+    fn test_buckets() {
+        setup();
+        let file = varied_file();
+        let reader = reader(&file);
+        let bucketing = Bucketing::Boundaries(vec![30, 50]);
+        let batches: Vec<Batch> = reader
+            .iter_buckets(&bucketing, 8, false)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(batches.iter().map(|batch| batch.len()).sum::<usize>(), 60);
+        let bucket = |(_, value): Sample| [30, 50].partition_point(|boundary| *boundary <= value.len());
+        for batch in batches.iter() {
+            let first = bucket(batch.get(0).unwrap());
+            assert!(batch.iter().all(|sample| bucket(sample) == first));
+        }
+
+        let dropped: Vec<Batch> = reader
+            .iter_buckets(&bucketing, 8, true)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(dropped.len(), 6);
+        assert!(dropped.iter().all(|batch| batch.len() == 8));
+    }
+
+    #[test]
+    fn test_buckets_resume() {
+        setup();
+        let file = varied_file();
+        let reader = reader(&file);
+        let bucketing = Bucketing::MaxWaste(0.3);
+        let expected: Vec<Batch> = reader
+            .iter_buckets(&bucketing, 5, false)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+
+        for taken in [0, 1, 4] {
+            let mut iter = reader.iter_buckets(&bucketing, 5, false).unwrap();
+            let mut batches: Vec<Batch> = iter.by_ref().take(taken).map(Result::unwrap).collect();
+            let state = BucketState::from_bytes(&iter.state().to_bytes().unwrap()).unwrap();
+            batches.extend(
+                reader
+                    .iter_buckets_from(&state, &bucketing, 5, false)
+                    .unwrap()
+                    .map(Result::unwrap),
+            );
+            assert_eq!(batches, expected);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Report, Result};
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt;
+
+use crate::reader::datasource::{AsyncDataSource, DataSource};
+
+/// Source the range was read from and the range.
+type Key = (usize, Range<usize>);
+type Fetch = Shared<BoxFuture<'static, std::result::Result<Bytes, Arc<Report>>>>;
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<Key, (Bytes, u64)>,
+    /// Keys of the entries by when they were last used.
+    order: BTreeMap<u64, Key>,
+    in_flight: HashMap<Key, Fetch>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &Key) -> Option<Bytes> {
+        self.tick += 1;
+        let (data, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, key: Key, data: Bytes, capacity: usize) {
+        if data.len() > capacity || self.entries.contains_key(&key) {
+            return;
+        }
+        while self.bytes + data.len() > capacity {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            let (evicted, _) = self.entries.remove(&oldest).unwrap();
+            self.bytes -= evicted.len();
+        }
+        self.tick += 1;
+        self.bytes += data.len();
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (data, self.tick));
+    }
+}
+
+/// Range reads kept in memory up to `capacity` bytes, the least recently used ones are evicted first.
+///
+/// Reads of a range already being fetched wait for that fetch instead of starting another one.
+pub(crate) struct BlockCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+impl BlockCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// An empty cache of the same capacity.
+    pub(crate) fn emptied(&self) -> Self {
+        Self::new(self.capacity)
+    }
+
+    pub(crate) async fn get(&self, key: Key, source: &DataSource) -> Result<Bytes> {
+        let fetch = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(data) = state.touch(&key) {
+                return Ok(data);
+            }
+            match state.in_flight.get(&key) {
+                Some(fetch) => fetch.clone(),
+                None => {
+                    let source = source.clone();
+                    let range = key.1.clone();
+                    let fetch = async move { source.get_range_async(range).await.map_err(Arc::new) }
+                        .boxed()
+                        .shared();
+                    state.in_flight.insert(key.clone(), fetch.clone());
+                    fetch
+                }
+            }
+        };
+        let read = fetch.await;
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight.remove(&key).is_some() {
+            if let Ok(data) = &read {
+                state.insert(key, data.clone(), self.capacity);
+            }
+        }
+        read.map_err(|err| eyre!("{err:#}"))
+    }
+}
+
+/// A source whose reads go through a [`BlockCache`], which may be shared with other sources.
+///
+/// It is async whatever `inner` is, as waiting for a fetch of another reader must not block the executor.
+pub(crate) struct CachedSource {
+    inner: DataSource,
+    cache: Arc<BlockCache>,
+    id: usize,
+}
+
+impl CachedSource {
+    /// `id` tells the reads of this source apart from the ones of other sources sharing the cache.
+    pub(crate) fn new(inner: DataSource, cache: Arc<BlockCache>, id: usize) -> Self {
+        Self { inner, cache, id }
+    }
+}
+
+#[async_trait]
+impl AsyncDataSource for CachedSource {
+    async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes> {
+        self.cache.get((self.id, range), &self.inner).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::executor::block_on;
+    use futures::future::join_all;
+
+    use super::*;
+    use crate::reader::datasource::SyncDataSource;
+    use crate::reader::retry::sleep;
+    use crate::reader::{Dataset, Reader};
+    use crate::test_setup::*;
+
+    /// Counts the reads of every range start, the reads take a while to finish.
+    #[derive(Default)]
+    struct Slow {
+        reads: Mutex<HashMap<usize, usize>>,
+        total: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl AsyncDataSource for Slow {
+        async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes> {
+            *self.reads.lock().unwrap().entry(range.start).or_default() += 1;
+            self.total.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_millis(20)).await;
+            Ok(Bytes::from(vec![range.start as u8; range.len()]))
+        }
+    }
+
+    #[test]
+    fn test_block_cache() {
+        setup();
+        let slow = Arc::new(Slow::default());
+        let cached = CachedSource::new(DataSource::Async(slow.clone()), Arc::new(BlockCache::new(100)), 0);
+
+        let concurrent = block_on(join_all((0..4).map(|_| cached.get_range_async(0..40))));
+        assert!(concurrent.into_iter().all(|read| read.unwrap() == vec![0; 40]));
+        assert_eq!(slow.total.load(Ordering::SeqCst), 1);
+
+        // 0 is used again, so 40 is the least recently used once 80 no longer fits.
+        for start in [40, 0, 80, 0, 40] {
+            cached.get_range(start..start + 40).unwrap();
+        }
+        let reads = slow.reads.lock().unwrap().clone();
+        assert_eq!(reads, HashMap::from([(0, 1), (40, 2), (80, 1)]));
+        assert!(cached.get_range(0..200).is_ok());
+        assert!(cached.cache.state.lock().unwrap().bytes <= 100);
+    }
+
+    #[test]
+    fn test_cached_reader() {
+        setup();
+        let file = new_dummy_path(32, 10).unwrap();
+        let path = file.path().to_str().unwrap();
+        let mut reader = Reader::new();
+        reader.open_file(path).unwrap().by_count(4).with_shuffling(Some(1));
+        let expected: Vec<_> = reader.iter().unwrap().map(Result::unwrap).collect();
+        reader.with_block_cache(1 << 20);
+        for _ in 0..2 {
+            assert_eq!(reader.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), expected);
+        }
+        reader.open_file_mmap(path).unwrap();
+        assert_eq!(reader.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), expected);
+
+        // Set before opening, and not serving reads of the archive opened before to the one opened after.
+        let other = new_dummy_path(32, 10).unwrap();
+        let other = other.path().to_str().unwrap();
+        let mut reader = Reader::new();
+        reader.with_block_cache(1 << 20).open_file(path).unwrap().by_count(4);
+        assert_eq!(reader.iter().unwrap().count(), 32);
+        reader.open_file(other).unwrap();
+        let expected: Vec<_> = Reader::new()
+            .open_file(other)
+            .unwrap()
+            .by_count(4)
+            .iter()
+            .unwrap()
+            .collect();
+        assert_eq!(
+            reader.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(),
+            expected.into_iter().map(Result::unwrap).collect::<Vec<_>>()
+        );
+
+        let mut dataset = Dataset::open_file(path).unwrap();
+        let sample = dataset.get_key("000007").unwrap();
+        dataset.with_block_cache(1 << 20);
+        assert_eq!(dataset.get_key("000007").unwrap(), sample);
+        assert_eq!(dataset.get_key("000007").unwrap(), sample);
+    }
+}
@@ -0,0 +1,253 @@
+use std::iter;
+use std::ops::Range;
+
+use color_eyre::eyre::{eyre, Result};
+use futures::future::join_all;
+
+use crate::reader::block::{Block, SampleId};
+use crate::reader::datasource::DataSource;
+use crate::reader::iter::ReadBlock;
+use crate::reader::policy::ErrorPolicy;
+use crate::reader::Sample;
+
+type Indexed = (usize, Block);
+
+/// Reads blocks close to each other in an archive with one request of at most `max_bytes`, also reading the gaps of up
+/// to `max_gap` bytes between them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Coalescing {
+    pub(crate) max_bytes: usize,
+    pub(crate) max_gap: usize,
+}
+
+impl Coalescing {
+    /// Groups of blocks read with one request, by archive and position in it.
+    fn group(&self, mut blocks: Vec<Indexed>) -> Vec<Vec<Indexed>> {
+        blocks.sort_by_key(|(_, block)| (block.archive(), block.contiguous_range().map(|range| range.start)));
+        let mut groups: Vec<Vec<Indexed>> = Vec::new();
+        let mut span: Option<(usize, Range<usize>)> = None;
+        for (idx, block) in blocks {
+            let range = block.contiguous_range();
+            let merged = match (&span, &range) {
+                (Some((archive, span)), Some(range))
+                    if *archive == block.archive()
+                        && range.start <= span.end + self.max_gap
+                        && range.end.max(span.end) - span.start <= self.max_bytes =>
+                {
+                    Some(span.start..range.end.max(span.end))
+                }
+                _ => None,
+            };
+            match merged {
+                Some(merged) => {
+                    span = Some((block.archive(), merged));
+                    groups.last_mut().unwrap().push((idx, block));
+                }
+                None => {
+                    span = range.map(|range| (block.archive(), range));
+                    groups.push(vec![(idx, block)]);
+                }
+            }
+        }
+        groups
+    }
+}
+
+/// Planned blocks in chunks of up to `max_bytes` of blocks, in order, the blocks of a chunk are read together.
+///
+/// Without coalescing every chunk is a single block.
+pub(crate) fn chunk_blocks<I>(
+    blocks: I,
+    coalescing: Option<Coalescing>,
+) -> impl Iterator<Item = Vec<(u64, Result<Block>)>>
+where
+    I: Iterator<Item = (u64, Result<Block>)>,
+{
+    let size = |(_, block): &(u64, Result<Block>)| block.as_ref().map_or(usize::MAX, Block::byte_size);
+    let mut blocks = blocks.peekable();
+    iter::from_fn(move || {
+        let mut chunk = vec![blocks.next()?];
+        let mut bytes = size(&chunk[0]);
+        while let Some(next) =
+            blocks.next_if(|next| coalescing.is_some_and(|c| bytes.saturating_add(size(next)) <= c.max_bytes))
+        {
+            bytes += size(&next);
+            chunk.push(next);
+        }
+        Some(chunk)
+    })
+}
+
+/// Reads the blocks of a chunk, the ones of an archive close to each other with one request.
+///
+/// If a merged read fails its blocks are read again one at a time, so the error policy applies to every block alone.
+pub(crate) async fn read_chunk(
+    chunk: Vec<(u64, Result<Block>)>,
+    sources: &[DataSource],
+    policy: ErrorPolicy,
+    coalescing: Option<Coalescing>,
+) -> Vec<ReadBlock> {
+    let mut epochs = Vec::with_capacity(chunk.len());
+    let mut reads = Vec::with_capacity(chunk.len());
+    let mut planned = Vec::new();
+    for (idx, (epoch, block)) in chunk.into_iter().enumerate() {
+        epochs.push(epoch);
+        match block {
+            Ok(block) => {
+                reads.push(None);
+                planned.push((idx, block));
+            }
+            Err(err) => reads.push(Some(Err(err))),
+        }
+    }
+
+    let groups = match coalescing {
+        Some(coalescing) => coalescing.group(planned),
+        None => planned.into_iter().map(|block| vec![block]).collect(),
+    };
+    let groups = join_all(groups.into_iter().map(|group| read_group(group, sources, policy))).await;
+    for (idx, read) in groups.into_iter().flatten() {
+        reads[idx] = Some(read);
+    }
+    epochs
+        .into_iter()
+        .zip(reads)
+        .map(|(epoch, read)| (epoch, read.unwrap()))
+        .collect()
+}
+
+async fn read_group(
+    group: Vec<Indexed>,
+    sources: &[DataSource],
+    policy: ErrorPolicy,
+) -> Vec<(usize, Result<Vec<(SampleId, Sample)>>)> {
+    if group.len() > 1 {
+        if let Ok(reads) = read_merged(&group, sources).await {
+            return reads;
+        }
+    }
+    let mut reads = Vec::with_capacity(group.len());
+    for (idx, block) in group {
+        let datasource = sources[block.archive()].clone();
+        reads.push((idx, policy.read_block(block, datasource).await));
+    }
+    reads
+}
+
+/// Reads the blocks of a group with one request spanning all of them.
+async fn read_merged(
+    group: &[Indexed],
+    sources: &[DataSource],
+) -> Result<Vec<(usize, Result<Vec<(SampleId, Sample)>>)>> {
+    let ranges = group
+        .iter()
+        .map(|(_, block)| block.contiguous_range().ok_or(eyre!("Invalid range")))
+        .collect::<Result<Vec<_>>>()?;
+    let start = ranges.iter().map(|range| range.start).min().unwrap_or(0);
+    let end = ranges.iter().map(|range| range.end).max().unwrap_or(0);
+    let data = sources[group[0].1.archive()].get_range_async(start..end).await?;
+    group
+        .iter()
+        .zip(ranges)
+        .map(|((idx, block), range)| {
+            let block = block
+                .clone()
+                .with_buffer(data.slice(range.start - start..range.end - start));
+            Ok((*idx, Ok(block.ids().zip(block.to_vec()?).collect())))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::header::Header;
+    use crate::reader::datasource::SyncDataSource;
+    use crate::reader::Reader;
+    use crate::test_setup::*;
+
+    struct Counting {
+        file: std::fs::File,
+        requests: AtomicUsize,
+    }
+
+    impl SyncDataSource for Counting {
+        fn get_range(&self, range: Range<usize>) -> Result<Bytes> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            self.file.get_range(range)
+        }
+    }
+
+    #[test]
+    fn test_coalescing() {
+        setup();
+        let file = new_dummy_path(32, 10).unwrap();
+        let header = Arc::new(Header::read(&mut file.reopen().unwrap()).unwrap());
+        let counting = Arc::new(Counting {
+            file: file.reopen().unwrap(),
+            requests: AtomicUsize::new(0),
+        });
+        let sources = [DataSource::Sync(counting.clone())];
+        let blocks = |order: &[usize]| {
+            let blocks = order
+                .iter()
+                .map(|idx| (0, Ok(Block::from_range(0, header.clone(), idx * 4..idx * 4 + 4))));
+            blocks.collect::<Vec<_>>()
+        };
+        let read = |order: &[usize], coalescing: Option<Coalescing>| {
+            counting.requests.store(0, Ordering::SeqCst);
+            let reads: Vec<Vec<String>> = chunk_blocks(blocks(order).into_iter(), coalescing)
+                .flat_map(|chunk| block_on(read_chunk(chunk, &sources, ErrorPolicy::Raise, coalescing)))
+                .map(|(_, read)| read.unwrap().into_iter().map(|(_, (key, _))| key).collect())
+                .collect();
+            (reads, counting.requests.load(Ordering::SeqCst))
+        };
+
+        let order = [3, 1, 0, 2, 5, 7, 6, 4];
+        let (expected, requests) = read(&order, None);
+        assert_eq!(expected[0][0], "000012");
+        assert_eq!(requests, 8);
+        let all = Some(Coalescing {
+            max_bytes: 1 << 20,
+            max_gap: 0,
+        });
+        assert_eq!(read(&order, all), (expected, 1));
+        // Chunks of two blocks, merged when they are next to each other in the file.
+        let pairs = Some(Coalescing {
+            max_bytes: 80,
+            max_gap: 0,
+        });
+        assert_eq!(read(&[1, 0, 2, 5, 7, 6], pairs).1, 4);
+        let gaps = Some(Coalescing {
+            max_bytes: 1 << 20,
+            max_gap: 40,
+        });
+        assert_eq!(read(&[0, 2, 6], gaps).1, 2);
+    }
+
+    #[test]
+    fn test_coalesced_reader() {
+        setup();
+        let file = new_dummy_path(64, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_sharding(1, 2)
+            .unwrap();
+        let expected: Vec<_> = reader.iter().unwrap().map(Result::unwrap).collect();
+        reader.with_coalescing(256, 64).with_buffering(4).unwrap();
+        assert_eq!(reader.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), expected);
+        reader.with_shuffling(Some(3));
+        let shuffled: Vec<_> = reader.iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(shuffled.len(), expected.len());
+        assert_ne!(shuffled, expected);
+    }
+}
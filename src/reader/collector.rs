@@ -1,15 +1,23 @@
 use std::cmp::min;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
 use color_eyre::eyre::{ensure, eyre, Result};
-use either::Either;
+use futures::executor::block_on;
 use rand::SeedableRng;
 use rand::seq::SliceRandom;
 use thiserror::Error;
 
-use crate::reader::block::Block;
+use crate::reader::block::{Block, Window, YieldedId};
 use crate::reader::bounded::BoundedIter;
-use crate::reader::datasource::DataSource;
-use crate::reader::readers::RcHeader;
+use crate::reader::coalesce::{chunk_blocks, read_chunk, Coalescing};
+use crate::reader::filter::{matching_runs, Filter};
+use crate::reader::iter::{BlockSamples, ReadBlock, ReaderState, SampleIter};
+use crate::reader::policy::ErrorPolicy;
+use crate::reader::readers::{ArcHeader, Archive};
+use crate::reader::sampling::Sampling;
+use crate::reader::shard::{shard_blocks, ShardMode};
+use crate::reader::shuffle::{BufferCriteria, BufferState, ShuffleBuffer};
 use crate::reader::Sample;
 
 #[derive(Clone, Copy, Debug)]
@@ -25,10 +33,16 @@ impl Default for CollectorCriteria {
 }
 
 impl CollectorCriteria {
-    fn size_collect_block(header: RcHeader, block_size: usize, start: usize) -> Result<Block> {
+    fn size_collect_block(
+        archive: usize,
+        header: ArcHeader,
+        block_size: usize,
+        start: usize,
+        end: usize,
+    ) -> Result<Block> {
         let mut size = 0;
         let range_size = header
-            .get_range(start..header.len())
+            .get_range(start..end)
             .ok_or(eyre!("Index out of bounds"))?
             .iter()
             .take_while(|(_, entry)| {
@@ -37,18 +51,19 @@ impl CollectorCriteria {
             })
             .count()
             .max(1);
-        Ok(Block::from_range(header, start..start + range_size))
+        Ok(Block::from_range(archive, header, start..start + range_size))
     }
 
-    fn count_collect_block(header: RcHeader, num_entries: usize, start: usize) -> Block {
-        let end = min(start + num_entries, header.len());
-        Block::from_range(header, start..end)
+    fn count_collect_block(archive: usize, header: ArcHeader, num_entries: usize, start: usize, end: usize) -> Block {
+        let end = min(start + num_entries, end);
+        Block::from_range(archive, header, start..end)
     }
 
-    fn collect(&self, header: RcHeader, start: usize) -> Result<Block> {
+    /// Next block starting at `start`, never reaching past `end`.
+    fn collect(&self, archive: usize, header: ArcHeader, start: usize, end: usize) -> Result<Block> {
         match self {
-            CollectorCriteria::Size(n) => Self::size_collect_block(header, *n, start),
-            CollectorCriteria::Count(n) => Ok(Self::count_collect_block(header, *n, start)),
+            CollectorCriteria::Size(n) => Self::size_collect_block(archive, header, *n, start, end),
+            CollectorCriteria::Count(n) => Ok(Self::count_collect_block(archive, header, *n, start, end)),
         }
     }
 }
@@ -59,14 +74,59 @@ pub enum CollectorError {
     InvalidRank(u16, u16),
     #[error("World_size must be greater than 0, got world_size: {0}")]
     InvalidWorldSize(u16),
+    #[error("Reader state was saved with a different seed, sharding, shuffle buffer or block plan")]
+    StateMismatch,
+    #[error("Unknown shard mode: {0}, expected one of uneven, drop_remainder, pad")]
+    InvalidShardMode(String),
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+/// 64-bit FNV-1a, unlike the std hasher its output is the same across builds, so it can be stored in states.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// How many epochs one iterator goes through before it ends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Repeat {
+    #[default]
+    Once,
+    Times(u64),
+    Forever,
+}
+
+#[derive(Clone, Debug, Default)]
 pub(crate) struct Collector {
     criteria: CollectorCriteria,
     shuffle: Option<u64>,
-    shard: Option<(u16, u16)>,
+    epoch: u64,
+    sample_shuffle: Option<BufferCriteria>,
+    shard: Vec<(u16, u16)>,
+    worker: Option<(u16, u16)>,
+    shard_mode: ShardMode,
+    shard_by_size: bool,
     buffer_size: Option<u32>,
+    repeat: Repeat,
+    sampling: Sampling,
+    filters: Vec<Filter>,
+    window: Option<Window>,
+    error_policy: ErrorPolicy,
+    coalescing: Option<Coalescing>,
 }
 
 impl Collector {
@@ -85,66 +145,790 @@ impl Collector {
         self
     }
 
-    pub(crate) fn with_sharding(&mut self, rank: u16, world_size: u16) -> Result<&mut Self> {
+    pub(crate) fn set_epoch(&mut self, epoch: u64) -> &mut Self {
+        self.epoch = epoch;
+        self
+    }
+
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub(crate) fn with_repeat(&mut self, repeat: Repeat) -> &mut Self {
+        self.repeat = repeat;
+        self
+    }
+
+    /// Epoch after the last one an iterator starting at `start` goes through, `None` if it never ends.
+    ///
+    /// Repeated epochs count from the configured epoch, so resuming an iterator does not extend them.
+    fn end_epoch(&self, start: u64) -> Option<u64> {
+        match self.repeat {
+            Repeat::Once => Some(start + 1),
+            Repeat::Times(epochs) => Some(self.epoch + epochs),
+            Repeat::Forever => None,
+        }
+    }
+
+    pub(crate) fn shuffle_buffer_by_size(&mut self, size: usize) -> &mut Self {
+        self.sample_shuffle = Some(BufferCriteria::Size(size));
+        self
+    }
+
+    pub(crate) fn shuffle_buffer_by_count(&mut self, count: usize) -> &mut Self {
+        self.sample_shuffle = Some(BufferCriteria::Count(count));
+        self
+    }
+
+    /// Seed for the block permutation of the current epoch, epoch 0 uses the base seed as is.
+    fn epoch_seed(&self) -> Option<u64> {
+        self.shuffle
+            .map(|seed| seed.wrapping_add(self.epoch.wrapping_mul(0x9E37_79B9_7F4A_7C15)))
+    }
+
+    fn check_rank(rank: u16, world_size: u16) -> Result<()> {
         ensure!(rank < world_size, CollectorError::InvalidRank(rank, world_size));
         ensure!(world_size > 0, CollectorError::InvalidWorldSize(world_size));
-        self.shard = Some((rank, world_size));
+        Ok(())
+    }
+
+    pub(crate) fn with_sharding(&mut self, rank: u16, world_size: u16) -> Result<&mut Self> {
+        Self::check_rank(rank, world_size)?;
+        self.shard = vec![(rank, world_size)];
         Ok(self)
     }
 
+    /// Splits the blocks of the current shard further, e.g. node, then rank within the node.
+    pub(crate) fn with_nested_sharding(&mut self, rank: u16, world_size: u16) -> Result<&mut Self> {
+        Self::check_rank(rank, world_size)?;
+        self.shard.push((rank, world_size));
+        Ok(self)
+    }
+
+    /// Innermost split over data loading workers, which is never balanced since workers of a rank are merged again.
+    pub(crate) fn with_worker_sharding(&mut self, worker: u16, num_workers: u16) -> Result<&mut Self> {
+        Self::check_rank(worker, num_workers)?;
+        self.worker = Some((worker, num_workers));
+        Ok(self)
+    }
+
+    pub(crate) fn with_shard_mode(&mut self, mode: ShardMode) -> &mut Self {
+        self.shard_mode = mode;
+        self
+    }
+
+    pub(crate) fn balance_shards_by_size(&mut self) -> &mut Self {
+        self.shard_by_size = true;
+        self
+    }
+
+    pub(crate) fn with_filter(&mut self, filter: Filter) -> &mut Self {
+        self.filters.push(filter);
+        self
+    }
+
+    pub(crate) fn with_window(&mut self, window: Window) -> &mut Self {
+        self.window = Some(window);
+        self
+    }
+
+    /// Blocks only reading the window of every sample this epoch crops out, if any.
+    fn windowed(&self, blocks: Vec<Block>) -> Vec<Block> {
+        match self.window {
+            Some(window) => {
+                let seed = self.epoch_seed().unwrap_or(self.epoch);
+                blocks
+                    .into_iter()
+                    .map(|block| block.with_window(window, seed))
+                    .collect()
+            }
+            None => blocks,
+        }
+    }
+
+    pub(crate) fn sampling(&mut self) -> &mut Sampling {
+        &mut self.sampling
+    }
+
     pub(crate) fn with_buffering(&mut self, buffer_size: u32) -> &mut Self {
         self.buffer_size = Some(buffer_size);
         self
     }
 
-    fn collect(&self, header: RcHeader) -> Result<Vec<Block>> {
-        let entries = header.entries();
+    pub(crate) fn with_coalescing(&mut self, coalescing: Coalescing) -> &mut Self {
+        self.coalescing = Some(coalescing);
+        self
+    }
+
+    /// Splits the samples passing the filters into blocks, a block never spans a filtered out sample.
+    fn collect(&self, archives: &[Archive]) -> Result<Vec<Block>> {
         let mut blocks = Vec::new();
-        let mut start = 0usize;
-        while start < entries.len() {
-            let block = self.criteria.collect(header.clone(), start)?;
-            start += block.len();
-            blocks.push(block);
+        for (archive, Archive { header, .. }) in archives.iter().enumerate() {
+            for run in matching_runs(header, &self.filters) {
+                let mut start = run.start;
+                while start < run.end {
+                    let block = self.criteria.collect(archive, header.clone(), start, run.end)?;
+                    start += block.len();
+                    blocks.push(block);
+                }
+            }
         }
         Ok(blocks)
     }
 
-    fn iter_blocks(&self, header: RcHeader) -> Result<impl Iterator<Item = Block>> {
-        let mut blocks = self.collect(header.clone())?;
-        if let Some(seed) = self.shuffle {
+    fn check_sampling(&self, archives: &[Archive]) -> Result<()> {
+        let lens: Vec<usize> = archives.iter().map(|archive| archive.header.len()).collect();
+        self.sampling.check(&lens)
+    }
+
+    fn iter_blocks(&self, archives: &[Archive]) -> Result<Vec<Block>> {
+        self.check_sampling(archives)?;
+        let mut blocks = self.collect(archives)?;
+        if self.sampling.is_active() {
+            blocks = self.sampling.draw(blocks, self.epoch_seed().unwrap_or(self.epoch));
+        } else if let Some(seed) = self.epoch_seed() {
             let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
             blocks.shuffle(&mut rng);
         }
-        let iter = blocks.into_iter();
-        match self.shard {
-            Some((rank, world_size)) => Ok(Either::Left(
-                iter.enumerate()
-                    .filter(move |(i, _)| *i as u16 % world_size == rank)
-                    .map(|(_, block)| block),
-            )),
-            None => Ok(Either::Right(iter)),
+        for (rank, world_size) in self.shard.iter() {
+            blocks = shard_blocks(blocks, *rank, *world_size, self.shard_mode, self.shard_by_size);
+        }
+        if let Some((worker, num_workers)) = self.worker {
+            blocks = shard_blocks(blocks, worker, num_workers, ShardMode::Uneven, self.shard_by_size);
+        }
+        Ok(self.windowed(blocks))
+    }
+
+    /// Blocks of every epoch from `start` on, skipping the first `skip` blocks of `start`.
+    ///
+    /// Epochs are planned lazily and the iteration stops early if an epoch has no blocks for this rank, or after an
+    /// epoch failed to be planned.
+    fn epoch_blocks(
+        &self,
+        archives: &[Archive],
+        start: u64,
+        skip: usize,
+    ) -> impl Iterator<Item = (u64, Result<Block>)> {
+        let collector = self.clone();
+        let archives = archives.to_vec();
+        let end = self.end_epoch(start);
+        let mut failed = false;
+        (start..)
+            .take_while(move |epoch| end.is_none_or(|end| *epoch < end))
+            .map_while(move |epoch| {
+                let mut collector = collector.clone();
+                collector.epoch = epoch;
+                let blocks = collector.iter_blocks(&archives);
+                match blocks {
+                    _ if failed => None,
+                    Ok(blocks) if blocks.is_empty() => None,
+                    Ok(blocks) => Some((epoch, Ok(blocks))),
+                    Err(err) => {
+                        failed = true;
+                        Some((epoch, Err(err)))
+                    }
+                }
+            })
+            .flat_map(move |(epoch, blocks)| {
+                let skip = if epoch == start { skip } else { 0 };
+                match blocks {
+                    Ok(blocks) => blocks.into_iter().skip(skip).map(|block| (epoch, Ok(block))).collect(),
+                    Err(err) => vec![(epoch, Err(err))],
+                }
+            })
+    }
+
+    /// Fingerprint of everything the blocks of an epoch depend on besides the seed, epoch and shards, which states
+    /// record as they are: the archives, block criteria and filters through the unshuffled blocks, sampling, shard
+    /// balancing and windows.
+    fn plan_fingerprint(&self, archives: &[Archive]) -> Result<u64> {
+        let mut hasher = Fnv::default();
+        for Archive { header, .. } in archives {
+            (header.len(), header.archive_size()).hash(&mut hasher);
+        }
+        for block in self.collect(archives)? {
+            (block.archive(), block.range()).hash(&mut hasher);
         }
+        self.sampling.hash(&mut hasher);
+        (self.shard_mode, self.shard_by_size, self.window).hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    /// Number of samples one epoch yields for this rank.
+    pub(crate) fn len(&self, archives: &[Archive]) -> Result<usize> {
+        Ok(self.iter_blocks(archives)?.iter().map(|block| block.len()).sum())
+    }
+
+    /// Byte length of every sample one epoch yields for this rank, taken from the headers.
+    pub(crate) fn sample_lengths(&self, archives: &[Archive]) -> Result<Vec<usize>> {
+        self.iter_blocks(archives)?
+            .iter()
+            .flat_map(|block| block.ids())
+            .map(|(archive, idx)| {
+                let (_, entry) = archives[archive].header.get_index(idx).ok_or(eyre!("Invalid range"))?;
+                Ok(self
+                    .window
+                    .map_or(entry.length(), |window| window.len.min(entry.length())))
+            })
+            .collect()
     }
 
-    fn add_buffering<I>(&self, data: DataSource, block_iter: I) -> impl Iterator<Item = Result<Block>>
+    pub(crate) fn with_error_policy(&mut self, policy: ErrorPolicy) -> &mut Self {
+        self.error_policy = policy;
+        self
+    }
+
+    /// Reads the blocks under the error policy, up to `buffer_size` chunks of them at once if buffering.
+    fn read_blocks<I>(&self, archives: &[Archive], block_iter: I) -> Box<dyn Iterator<Item = ReadBlock> + Send>
     where
-        I: Iterator<Item = Block>,
+        I: Iterator<Item = (u64, Result<Block>)> + Send + 'static,
     {
-        let ds = data.into_async().unwrap();
-        let futures = block_iter.map(|block| block.read_async(ds.clone())).collect();
-        BoundedIter::from_vec(futures, self.buffer_size.unwrap() as usize)
+        let ds: Arc<Vec<_>> = Arc::new(archives.iter().map(|archive| archive.datasource.clone()).collect());
+        let policy = self.error_policy;
+        let coalescing = self.coalescing;
+        let futures = chunk_blocks(block_iter, coalescing).map(move |chunk| {
+            let ds = ds.clone();
+            async move { read_chunk(chunk, &ds, policy, coalescing).await }
+        });
+        match self.buffer_size {
+            Some(buffer_size) => Box::new(BoundedIter::new(futures, buffer_size as usize).flatten()),
+            None => Box::new(futures.flat_map(block_on)),
+        }
     }
 
-    pub(crate) fn iter(&self, header: RcHeader, data: DataSource) -> impl Iterator<Item = Sample> {
-        match self.buffer_size {
-            Some(_) => Either::Left(
-                self.add_buffering(data, self.iter_blocks(header).unwrap())
-                    .flat_map(|block| block.unwrap().to_vec().unwrap().into_iter()),
-            ),
-            None => Either::Right(self.iter_blocks(header).unwrap().flat_map(move |block| {
-                let data = data.clone().into_sync().unwrap();
-                block.read(data).unwrap().to_vec().unwrap().into_iter()
-            })),
+    /// Reads single samples as they were yielded in their epoch, e.g. the ones a saved buffer was holding.
+    pub(crate) fn read_ids(&self, archives: &[Archive], ids: &[YieldedId]) -> Result<Vec<Sample>> {
+        let mut collector = self.clone();
+        ids.iter()
+            .map(|(epoch, (archive, idx))| {
+                collector.epoch = *epoch;
+                let Archive { header, datasource, .. } = archives.get(*archive).ok_or(eyre!("Invalid archive"))?;
+                let block = Block::from_range(*archive, header.clone(), *idx..*idx + 1);
+                let block = collector.windowed(vec![block]).pop().unwrap().read_from(datasource)?;
+                block.to_vec()?.pop().ok_or(eyre!("Invalid range"))
+            })
+            .collect()
+    }
+
+    fn restore_buffer(
+        &self,
+        archives: &[Archive],
+        criteria: BufferCriteria,
+        state: &BufferState,
+    ) -> Result<ShuffleBuffer> {
+        let samples = self.read_ids(archives, &state.ids)?;
+        Ok(ShuffleBuffer::restore(criteria, state, samples))
+    }
+
+    pub(crate) fn state(
+        &self,
+        epoch: u64,
+        block: usize,
+        sample: usize,
+        buffer: Option<BufferState>,
+        plan: u64,
+    ) -> ReaderState {
+        ReaderState {
+            seed: self.shuffle,
+            epoch,
+            shard: self.shard.clone(),
+            worker: self.worker,
+            block,
+            sample,
+            buffer,
+            plan,
+        }
+    }
+
+    /// Iterates from the configured epoch, or from the position of `state`, until the last repeated epoch.
+    pub(crate) fn iter(&self, archives: &[Archive], state: Option<&ReaderState>) -> Result<SampleIter> {
+        self.check_sampling(archives)?;
+        let plan = self.plan_fingerprint(archives)?;
+        if let Some(state) = state {
+            ensure!(
+                state.seed == self.shuffle
+                    && state.shard == self.shard
+                    && state.worker == self.worker
+                    && state.buffer.is_some() == self.sample_shuffle.is_some()
+                    && state.plan == plan,
+                CollectorError::StateMismatch
+            );
+        }
+
+        let (epoch, block, sample) = state.map_or((self.epoch, 0, 0), |state| (state.epoch, state.block, state.sample));
+        let blocks = self.epoch_blocks(archives, epoch, block.saturating_sub(1));
+        let samples = BlockSamples::new(self.read_blocks(archives, blocks), epoch, block, sample);
+        let buffer = match (self.sample_shuffle, state.and_then(|state| state.buffer.as_ref())) {
+            (Some(criteria), Some(buffer)) => Some(self.restore_buffer(archives, criteria, buffer)?),
+            (Some(criteria), None) => {
+                let seed = self.epoch_seed().unwrap_or(self.epoch);
+                Some(ShuffleBuffer::new(criteria, seed))
+            }
+            (None, _) => None,
+        };
+        Ok(SampleIter::new(self.clone(), samples, buffer, plan))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::reader::{Reader, ReaderError, SamplingError};
+    use crate::test_setup::*;
+
+    fn keys(reader: &Reader) -> Vec<String> {
+        reader.iter().unwrap().map(|sample| sample.unwrap().0).collect()
+    }
+
+    #[test]
+    fn test_epoch_reshuffles() {
+        setup();
+        let file = new_dummy_path(64, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_shuffling(Some(7));
+
+        let epoch_0 = keys(&reader);
+        assert_eq!(epoch_0, keys(reader.set_epoch(0)));
+        let epoch_1 = keys(reader.set_epoch(1));
+        assert_ne!(epoch_0, epoch_1);
+        assert_eq!(epoch_1, keys(reader.set_epoch(1)));
+
+        let mut sorted = epoch_1.clone();
+        sorted.sort();
+        assert_eq!(sorted, keys(reader.with_shuffling(None)));
+    }
+
+    #[test]
+    fn test_shuffle_buffer_mixes_blocks() {
+        setup();
+        let file = new_dummy_path(64, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(8)
+            .with_shuffling(Some(7));
+        let blocks_only = keys(&reader);
+
+        let mixed = keys(reader.shuffle_buffer_by_count(16).unwrap());
+        assert_ne!(blocks_only, mixed);
+        assert_eq!(mixed, keys(&reader));
+
+        let mut sorted = mixed.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..64).map(|i| format!("{i:06}")).collect::<Vec<_>>());
+
+        for err in [
+            reader.shuffle_buffer_by_count(0).err().unwrap(),
+            reader.shuffle_buffer_by_size(0).err().unwrap(),
+        ] {
+            assert!(matches!(err.downcast_ref(), Some(ReaderError::InvalidShuffleBuffer)));
+        }
+        assert_eq!(keys(&reader), mixed);
+    }
+
+    #[test]
+    fn test_epoch_shards_are_disjoint() {
+        setup();
+        let file = new_dummy_path(64, 10).unwrap();
+        let path = file.path().to_str().unwrap();
+        let mut all: Vec<String> = (0..4)
+            .flat_map(|rank| {
+                let mut reader = Reader::new();
+                reader
+                    .open_file(path)
+                    .unwrap()
+                    .by_count(4)
+                    .with_shuffling(Some(7))
+                    .set_epoch(3)
+                    .with_sharding(rank, 4)
+                    .unwrap();
+                keys(&reader)
+            })
+            .collect();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 64);
+    }
+
+    fn assert_resumes(reader: &Reader) {
+        let expected = keys(reader);
+        for taken in [0, 1, 5, 17, 40, 64] {
+            let mut iter = reader.iter().unwrap();
+            let head: Vec<String> = iter.by_ref().take(taken).map(|sample| sample.unwrap().0).collect();
+            let state = ReaderState::from_bytes(&iter.state().to_bytes().unwrap()).unwrap();
+            let tail: Vec<String> = reader
+                .iter_from(&state)
+                .unwrap()
+                .map(|sample| sample.unwrap().0)
+                .collect();
+            assert_eq!([head, tail].concat(), expected);
+        }
+    }
+
+    #[test]
+    fn test_resume() {
+        setup();
+        let file = new_dummy_path(64, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(6)
+            .with_shuffling(Some(7))
+            .set_epoch(2);
+        assert_resumes(&reader);
+        assert_resumes(reader.shuffle_buffer_by_count(10).unwrap());
+        assert_resumes(reader.shuffle_buffer_by_size(35).unwrap().with_sharding(1, 3).unwrap());
+    }
+
+    #[test]
+    fn test_buffering() {
+        setup();
+        let file = new_dummy_path(64, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(3)
+            .with_shuffling(Some(7))
+            .repeat(2);
+        let expected = keys(&reader);
+        assert_eq!(keys(reader.with_buffering(8).unwrap()), expected);
+        assert_resumes(reader.shuffle_buffer_by_count(10).unwrap());
+
+        let other = new_dummy_path(64, 10).unwrap();
+        let paths = [file.path(), other.path()];
+        let mut reader = Reader::new();
+        reader.open_files(&paths).unwrap().by_count(3).with_shuffling(Some(7));
+        let expected = keys(&reader);
+        assert_eq!(keys(reader.with_buffering(8).unwrap()), expected);
+
+        let mut mapped = Reader::new();
+        mapped
+            .open_file_mmap(file.path().to_str().unwrap())
+            .unwrap()
+            .with_block_cache(1 << 20);
+        assert!(mapped.with_buffering(8).is_err());
+    }
+
+    #[test]
+    fn test_resume_mismatch() {
+        setup();
+        let file = new_dummy_path(16, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .with_shuffling(Some(7));
+        let state = reader.iter().unwrap().state();
+        reader.with_shuffling(Some(8));
+        let err = reader.iter_from(&state).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(CollectorError::StateMismatch)));
+
+        // Changes to the block plan move what the saved block and sample positions point to.
+        reader.with_shuffling(Some(7)).by_count(2);
+        let state = reader.iter().unwrap().state();
+        assert!(reader.clone().by_count(3).iter_from(&state).is_err());
+        assert!(reader.clone().filter_prefix("00000").iter_from(&state).is_err());
+        assert!(reader.clone().with_replacement(None).iter_from(&state).is_err());
+        let other = new_dummy_path(17, 10).unwrap();
+        let mut reopened = reader.clone();
+        reopened.open_file(other.path().to_str().unwrap()).unwrap();
+        assert!(reopened.iter_from(&state).is_err());
+        assert!(reader.iter_from(&state).is_ok());
+    }
+
+    #[test]
+    fn test_repeat() {
+        setup();
+        let file = new_dummy_path(32, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_shuffling(Some(7))
+            .set_epoch(1);
+        let epochs: Vec<String> = (1..4).flat_map(|epoch| keys(reader.set_epoch(epoch))).collect();
+
+        reader.set_epoch(1).repeat(3);
+        assert_eq!(keys(&reader), epochs);
+        let mut iter = reader.iter().unwrap();
+        assert_eq!(iter.by_ref().take(33).count(), 33);
+        assert_eq!(iter.epoch(), 2);
+        assert_resumes(&reader);
+
+        let forever: Vec<String> = reader
+            .repeat_forever()
+            .iter()
+            .unwrap()
+            .take(200)
+            .map(|sample| sample.unwrap().0)
+            .collect();
+        assert_eq!(forever[..96], epochs);
+        assert_eq!(forever.len(), 200);
+        assert_resumes(reader.repeat(3).shuffle_buffer_by_count(10).unwrap());
+    }
+
+    #[test]
+    fn test_weighted_sampling() {
+        setup();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = crate::archive::Writer::new(file.as_file().try_clone().unwrap(), 1024, 10 * 1024).unwrap();
+        let weights: Vec<f32> = (0..17).map(|i| (i % 2) as f32).collect();
+        for i in 0..16 {
+            writer.write(&format!("{i:06}"), &generate_random_value(10)).unwrap();
+        }
+        let stored: Vec<u8> = weights.iter().flat_map(|weight| weight.to_le_bytes()).collect();
+        writer.write("weights", &stored).unwrap();
+        writer.close().unwrap();
+
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_shuffling(Some(7))
+            .with_stored_weights("weights")
+            .unwrap();
+        let mut odd = keys(&reader);
+        assert_eq!(reader.len().unwrap(), 8);
+        assert_eq!(odd, keys(&reader));
+        odd.sort();
+        assert_eq!(
+            odd,
+            (0..16)
+                .filter(|i| i % 2 == 1)
+                .map(|i| format!("{i:06}"))
+                .collect::<Vec<_>>()
+        );
+
+        let mut weights = vec![1.0; 17];
+        weights[16] = 0.0;
+        reader
+            .with_sample_weights(&weights)
+            .unwrap()
+            .with_replacement(Some(40))
+            .with_sharding(0, 2)
+            .unwrap();
+        let mut all = keys(&reader);
+        all.extend(keys(reader.with_sharding(1, 2).unwrap()));
+        assert_eq!(all.len(), 40);
+        assert!(all.iter().all(|key| key != "weights"));
+
+        let err = reader.with_sample_weights(&[1.0]).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(SamplingError::LengthMismatch(1, 17))));
+
+        // Weights given for the archive opened before do not fit the one replacing it.
+        let other = new_dummy_path(8, 10).unwrap();
+        reader.open_file(other.path().to_str().unwrap()).unwrap();
+        let err = reader.iter().err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(SamplingError::ArchiveMismatch(..))));
+        assert!(reader.len().is_err());
+    }
+
+    #[test]
+    fn test_filters() {
+        setup();
+        let file = new_dummy_path(64, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_shuffling(Some(7))
+            .filter_prefix("00001")
+            .exclude_keys(["000012".to_string()]);
+        let mut filtered = keys(&reader);
+        filtered.sort();
+        let expected: Vec<String> = (10..20).filter(|i| *i != 12).map(|i| format!("{i:06}")).collect();
+        assert_eq!(filtered, expected);
+        assert_eq!(reader.len().unwrap(), 9);
+
+        reader.filter_regex("[13579]$").unwrap().with_sharding(0, 2).unwrap();
+        let mut sharded = keys(&reader);
+        sharded.extend(keys(reader.with_sharding(1, 2).unwrap()));
+        sharded.sort();
+        assert_eq!(sharded, ["000011", "000013", "000015", "000017", "000019"]);
+
+        reader.filter(|key, _| key != "000015").filter_size(10..=10);
+        assert_eq!(reader.len().unwrap(), 2);
+        assert!(keys(reader.filter_size(0..=9)).is_empty());
+        assert!(Reader::new().filter_regex("(").is_err());
+    }
+
+    #[test]
+    fn test_random_window() {
+        setup();
+        let file = new_dummy_path(32, 100).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_shuffling(Some(7));
+        let full: Vec<Sample> = reader.iter().unwrap().map(Result::unwrap).collect();
+        let (key, value) = &full[3];
+        assert_eq!(reader.read_range(key, 10..30).unwrap(), value.slice(10..30));
+        let err = reader.read_range(key, 90..101).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ReaderError::RangeOutOfBounds(_, 100))
+        ));
+
+        reader
+            .with_random_window(16, 4)
+            .unwrap()
+            .shuffle_buffer_by_count(5)
+            .unwrap();
+        let windows: Vec<Sample> = reader.iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(windows.len(), 32);
+        for (key, window) in windows.iter() {
+            let (_, value) = full.iter().find(|(full_key, _)| full_key == key).unwrap();
+            let offset = (0..=84)
+                .step_by(4)
+                .find(|offset| value.slice(*offset..offset + 16) == window);
+            assert!(offset.is_some(), "{key}");
+        }
+        let mut iter = reader.iter().unwrap();
+        let mut resumed: Vec<Sample> = iter.by_ref().take(13).map(Result::unwrap).collect();
+        resumed.extend(reader.iter_from(&iter.state()).unwrap().map(Result::unwrap));
+        assert_eq!(resumed, windows);
+
+        reader.repeat(2);
+        let repeated: Vec<Sample> = reader.iter().unwrap().map(Result::unwrap).collect();
+        let mut iter = reader.iter().unwrap();
+        let mut resumed: Vec<Sample> = iter.by_ref().take(30).map(Result::unwrap).collect();
+        resumed.extend(reader.iter_from(&iter.state()).unwrap().map(Result::unwrap));
+        assert_eq!(resumed, repeated);
+        reader.repeat(1);
+        reader.set_epoch(1);
+        assert_ne!(reader.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), windows);
+        assert!(reader.with_random_window(16, 0).is_err());
+
+        let paths = [file.path(), file.path()];
+        let err = Reader::new()
+            .open_files(&paths)
+            .unwrap()
+            .read_range(key, 10..30)
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(ReaderError::AmbiguousKey(_, 2))));
+    }
+
+    #[test]
+    fn test_readers_on_threads() {
+        fn assert_send<T: Send>(_: &T) {}
+        setup();
+        let file = new_dummy_path(64, 10).unwrap();
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_shuffling(Some(7))
+            .filter(|key, _| key != "000003");
+        assert_send(&reader.iter().unwrap());
+
+        let handles: Vec<_> = (0..4)
+            .map(|rank| {
+                let mut reader = reader.clone();
+                reader.with_sharding(rank, 4).unwrap();
+                std::thread::spawn(move || keys(&reader))
+            })
+            .collect();
+        let mut sharded: Vec<String> = handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+        sharded.sort();
+        let expected: Vec<String> = (0..64).filter(|i| *i != 3).map(|i| format!("{i:06}")).collect();
+        assert_eq!(sharded, expected);
+    }
+
+    #[test]
+    fn test_nested_sharding() {
+        setup();
+        let file = new_dummy_path(64, 10).unwrap();
+        let path = file.path().to_str().unwrap();
+        let reader = |node: u16, rank: u16, worker: Option<u16>| {
+            let mut reader = Reader::new();
+            reader
+                .open_file(path)
+                .unwrap()
+                .by_count(5)
+                .with_shuffling(Some(7))
+                .with_shard_mode(ShardMode::DropRemainder)
+                .with_sharding(node, 2)
+                .unwrap()
+                .with_nested_sharding(rank, 2)
+                .unwrap();
+            if let Some(worker) = worker {
+                reader.with_worker_sharding(worker, 3).unwrap();
+            }
+            reader
+        };
+
+        let mut all = Vec::new();
+        for (node, rank) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            let rank_keys = keys(&reader(node, rank, None));
+            assert_eq!(rank_keys.len(), 15);
+            let mut worker_keys: Vec<String> = (0..3)
+                .flat_map(|worker| keys(&reader(node, rank, Some(worker))))
+                .collect();
+            worker_keys.sort();
+            let mut sorted = rank_keys.clone();
+            sorted.sort();
+            assert_eq!(worker_keys, sorted);
+            all.extend(rank_keys);
+        }
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 60);
+    }
+
+    #[test]
+    fn test_multiple_archives() {
+        setup();
+        let files: Vec<_> = (0..4).map(|_| new_dummy_path(16, 10).unwrap()).collect();
+        let paths: Vec<_> = files.iter().map(|file| file.path()).collect();
+        let mut reader = Reader::new();
+        reader.open_files(&paths).unwrap().with_max_open_files(2).by_count(4);
+        // Every archive uses the same keys, so the unshuffled order repeats them once per archive.
+        let expected: Vec<String> = (0..4).flat_map(|_| (0..16).map(|i| format!("{i:06}"))).collect();
+        assert_eq!(keys(&reader), expected);
+        assert_eq!(reader.len().unwrap(), 64);
+
+        reader.with_shuffling(Some(7)).with_sharding(0, 2).unwrap();
+        let mut sharded = keys(&reader);
+        sharded.extend(keys(reader.with_sharding(1, 2).unwrap()));
+        sharded.sort();
+        let mut expected = expected;
+        expected.sort();
+        assert_eq!(sharded, expected);
+    }
+
+    #[test]
+    fn test_open_manifest() {
+        setup();
+        let files: Vec<_> = (0..3).map(|_| new_dummy_path(8, 10).unwrap()).collect();
+        let dir = files[0].path().parent().unwrap();
+        let mut manifest = tempfile::NamedTempFile::new_in(dir).unwrap();
+        writeln!(manifest, "# shards").unwrap();
+        for file in files.iter() {
+            writeln!(manifest, "{}", file.path().file_name().unwrap().to_str().unwrap()).unwrap();
         }
+        let mut reader = Reader::new();
+        reader.open_manifest(manifest.path().to_str().unwrap()).unwrap();
+        assert_eq!(reader.len().unwrap(), 24);
+
+        let err = Reader::new()
+            .open_glob(&format!("{}/*.missing", dir.display()))
+            .err()
+            .unwrap();
+        assert!(matches!(err.downcast_ref(), Some(ReaderError::NoArchives(_))));
     }
 }
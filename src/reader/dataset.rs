@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::sync::Arc;
+
+use color_eyre::eyre::{Result, WrapErr};
+use thiserror::Error;
+
+use crate::header::Header;
+use crate::reader::cache::{BlockCache, CachedSource};
+use crate::reader::datasource::{DataSource, SyncDataSource};
+use crate::reader::Sample;
+
+#[derive(Error, Debug)]
+pub enum DatasetError {
+    #[error("Index {0} out of range for dataset of length {1}")]
+    IndexOutOfRange(usize, usize),
+    #[error("Key: {0} not found")]
+    KeyNotFound(String),
+}
+
+/// Random access view over an archive, addressed by position in the header or by key.
+pub struct Dataset {
+    header: Header,
+    datasource: Arc<dyn SyncDataSource>,
+}
+
+impl Dataset {
+    pub fn open_file(path: &str) -> Result<Self> {
+        let mut data = File::open(path).wrap_err_with(|| format!("Failed to open file from {}", path))?;
+        let header = Header::read(&mut data)?;
+        Ok(Self {
+            header,
+            datasource: Arc::new(data),
+        })
+    }
+
+    /// Keeps up to `capacity` bytes of samples read in memory.
+    pub fn with_block_cache(&mut self, capacity: usize) -> &mut Self {
+        let inner = DataSource::Sync(self.datasource.clone());
+        self.datasource = Arc::new(CachedSource::new(inner, Arc::new(BlockCache::new(capacity)), 0));
+        self
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.header.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.header.len()
+    }
+
+    pub fn get_index(&self, index: usize) -> Result<Sample> {
+        let (key, entry) = self
+            .header
+            .get_index(index)
+            .ok_or(DatasetError::IndexOutOfRange(index, self.header.len()))?;
+        let start = self.header.byte_size() + entry.start_idx();
+        let value = self.datasource.get_range(start..start + entry.length())?;
+        Ok((key.to_owned(), value))
+    }
+
+    pub fn get_key(&self, key: &str) -> Result<Sample> {
+        let index = self
+            .header
+            .entries()
+            .get_index_of(key)
+            .ok_or(DatasetError::KeyNotFound(key.to_string()))?;
+        self.get_index(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::Reader;
+    use crate::test_setup::*;
+
+    #[test]
+    fn test_dataset_matches_reader() {
+        setup();
+        let file = new_dummy_path(20, 10).unwrap();
+        let path = file.path().to_str().unwrap();
+        let dataset = Dataset::open_file(path).unwrap();
+        assert_eq!(dataset.len(), 20);
+
+        let samples: Vec<Sample> = Reader::new()
+            .open_file(path)
+            .unwrap()
+            .iter()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        for (i, sample) in samples.iter().enumerate() {
+            assert_eq!(dataset.get_index(i).unwrap(), *sample);
+            assert_eq!(dataset.get_key(&sample.0).unwrap(), *sample);
+        }
+    }
+
+    #[test]
+    fn test_dataset_missing() {
+        setup();
+        let file = new_dummy_path(5, 10).unwrap();
+        let dataset = Dataset::open_file(file.path().to_str().unwrap()).unwrap();
+        let err = dataset.get_index(5).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(DatasetError::IndexOutOfRange(5, 5))));
+        let err = dataset.get_key("missing").unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(DatasetError::KeyNotFound(_))));
+    }
+}
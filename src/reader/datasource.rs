@@ -1,18 +1,24 @@
-use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
 use std::ops::Range;
-use std::rc::Rc;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{ensure, eyre, Result, WrapErr};
+use futures::channel::oneshot;
 use futures::executor::block_on;
 #[cfg(feature = "gcs")]
 use gcs_reader::GCSReader;
+use memmap2::Mmap;
 
 #[async_trait]
-pub trait AsyncDataSource {
+pub trait AsyncDataSource: Send + Sync {
     async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes>;
 }
 
@@ -24,38 +30,257 @@ impl AsyncDataSource for GCSReader {
     }
 }
 
-pub trait SyncDataSource {
-    fn get_range(&mut self, range: Range<usize>) -> Result<Bytes>;
+/// A source read from any number of threads at once, so reads must not depend on a shared cursor.
+pub trait SyncDataSource: Send + Sync {
+    fn get_range(&self, range: Range<usize>) -> Result<Bytes>;
 }
 
 impl<T: AsyncDataSource> SyncDataSource for T {
-    fn get_range(&mut self, range: Range<usize>) -> Result<Bytes> {
+    fn get_range(&self, range: Range<usize>) -> Result<Bytes> {
         block_on(self.get_range_async(range))
     }
 }
 
 impl SyncDataSource for File {
-    fn get_range(&mut self, range: Range<usize>) -> Result<Bytes> {
+    fn get_range(&self, range: Range<usize>) -> Result<Bytes> {
         let mut buf = BytesMut::zeroed(range.len());
-        self.seek(SeekFrom::Start(range.start as u64))?;
-        self.read_exact(&mut buf)?;
+        read_exact_at(self, &mut buf, range.start as u64)?;
         Ok(buf.freeze())
     }
 }
 
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads running blocking reads, so several reads can be in flight at once.
+///
+/// A pool without threads runs every read on the thread awaiting it, which is all an unbuffered reader needs. Threads
+/// are started by the first read of every process, as a process forked from a reader, e.g. a DataLoader worker, does
+/// not inherit the threads of its parent.
+#[derive(Default)]
+pub(crate) struct ReadPool {
+    threads: AtomicUsize,
+    workers: Mutex<Option<Workers>>,
+}
+
+/// Threads started by one process and the channel feeding them.
+struct Workers {
+    pid: u32,
+    sender: Sender<Job>,
+    receiver: Arc<Mutex<Receiver<Job>>>,
+    started: usize,
+}
+
+impl Workers {
+    fn new(pid: u32) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        Self {
+            pid,
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            started: 0,
+        }
+    }
+
+    fn start(&mut self, threads: usize) {
+        for _ in self.started..threads {
+            let receiver = self.receiver.clone();
+            // Workers only stop once the pool, and with it the sender, is dropped.
+            thread::spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break,
+                }
+            });
+        }
+        self.started = self.started.max(threads);
+    }
+}
+
+impl ReadPool {
+    /// Uses at least `threads` threads from the next read on, a pool never shrinks.
+    pub(crate) fn grow(&self, threads: usize) {
+        self.threads.fetch_max(threads, Ordering::SeqCst);
+    }
+
+    pub(crate) fn threads(&self) -> usize {
+        self.threads.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn spawn(&self, job: Job) {
+        let threads = self.threads();
+        if threads == 0 {
+            return job();
+        }
+        let pid = process::id();
+        let mut workers = self.workers.lock().unwrap();
+        if workers.as_ref().is_none_or(|workers| workers.pid != pid) {
+            *workers = Some(Workers::new(pid));
+        }
+        let workers = workers.as_mut().unwrap();
+        workers.start(threads);
+        workers.sender.send(job).unwrap();
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A local file read with positional reads on a [`ReadPool`], so it can be buffered like a remote source.
+///
+/// Unbuffered, the pool has no threads and reads are as direct as those of a plain file.
+pub(crate) struct ThreadedFile {
+    file: Arc<File>,
+    pool: Arc<ReadPool>,
+}
+
+impl ThreadedFile {
+    pub(crate) fn new(file: File, pool: Arc<ReadPool>) -> Self {
+        Self {
+            file: Arc::new(file),
+            pool,
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncDataSource for ThreadedFile {
+    async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes> {
+        read_on(&self.pool, self.file.clone(), range).await
+    }
+}
+
+/// Reads `range` of `file` with a positional read on `pool`.
+async fn read_on(pool: &ReadPool, file: Arc<File>, range: Range<usize>) -> Result<Bytes> {
+    let (sender, receiver) = oneshot::channel();
+    pool.spawn(Box::new(move || {
+        let mut buf = BytesMut::zeroed(range.len());
+        let read = read_exact_at(&file, &mut buf, range.start as u64).map(|_| buf.freeze());
+        let _ = sender.send(read);
+    }));
+    Ok(receiver.await.wrap_err("Read thread stopped")??)
+}
+
+/// A local file mapped into memory, reads are slices of the mapping and copy nothing.
+///
+/// The file must not be truncated or modified while mapped.
+pub(crate) struct MappedFile {
+    data: Bytes,
+}
+
+impl MappedFile {
+    /// Maps `file`, or hands it back if it cannot be mapped, e.g. when it is larger than the address space.
+    pub(crate) fn map(file: File) -> std::result::Result<Self, File> {
+        // SAFETY: the mapping is read-only and the archive is not expected to change while being read.
+        match unsafe { Mmap::map(&file) } {
+            Ok(mmap) => Ok(Self {
+                data: Bytes::from_owner(mmap),
+            }),
+            Err(_) => Err(file),
+        }
+    }
+}
+
+impl SyncDataSource for MappedFile {
+    fn get_range(&self, range: Range<usize>) -> Result<Bytes> {
+        ensure!(
+            range.start <= range.end && range.end <= self.data.len(),
+            eyre!("Range {range:?} out of bounds for file of length {}", self.data.len())
+        );
+        Ok(self.data.slice(range))
+    }
+}
+
+/// Keeps at most `max_open` files open, closing the least recently used one to make room for another.
+pub(crate) struct FilePool {
+    max_open: usize,
+    open: VecDeque<(PathBuf, Arc<File>)>,
+}
+
+impl Default for FilePool {
+    fn default() -> Self {
+        Self {
+            max_open: 64,
+            open: VecDeque::new(),
+        }
+    }
+}
+
+impl FilePool {
+    pub(crate) fn set_max_open(&mut self, max_open: usize) {
+        self.max_open = max_open.max(1);
+        self.open.truncate(self.max_open);
+    }
+
+    fn get(&mut self, path: &Path) -> Result<Arc<File>> {
+        match self.open.iter().position(|(open, _)| open == path) {
+            Some(idx) => {
+                let entry = self.open.remove(idx).unwrap();
+                self.open.push_front(entry);
+            }
+            None => {
+                let file = File::open(path).wrap_err_with(|| format!("Failed to open file from {}", path.display()))?;
+                self.open.truncate(self.max_open - 1);
+                self.open.push_front((path.to_path_buf(), Arc::new(file)));
+            }
+        }
+        Ok(self.open[0].1.clone())
+    }
+}
+
+/// A file that is only opened through its [`FilePool`] when read from, read on a [`ReadPool`] like a
+/// [`ThreadedFile`].
+pub(crate) struct PooledFile {
+    path: PathBuf,
+    files: Arc<Mutex<FilePool>>,
+    pool: Arc<ReadPool>,
+}
+
+impl PooledFile {
+    pub(crate) fn new(path: PathBuf, files: Arc<Mutex<FilePool>>, pool: Arc<ReadPool>) -> Self {
+        Self { path, files, pool }
+    }
+}
+
+#[async_trait]
+impl AsyncDataSource for PooledFile {
+    /// The file pool is only locked to look the file up, the read itself runs concurrently with others.
+    async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes> {
+        let file = self.files.lock().unwrap().get(&self.path)?;
+        read_on(&self.pool, file, range).await
+    }
+}
+
 #[derive(Clone)]
 pub enum DataSource {
-    Sync(Rc<RefCell<dyn SyncDataSource>>),
-    Async(Rc<dyn AsyncDataSource>),
+    Sync(Arc<dyn SyncDataSource>),
+    Async(Arc<dyn AsyncDataSource>),
 }
 
 impl DataSource {
     pub fn new_sync<D: SyncDataSource + 'static>(data_source: D) -> Self {
-        Self::Sync(Rc::new(RefCell::new(data_source)))
+        Self::Sync(Arc::new(data_source))
     }
 
     pub fn new_async<D: AsyncDataSource + 'static>(data_source: D) -> Self {
-        Self::Async(Rc::new(data_source))
+        Self::Async(Arc::new(data_source))
     }
 
     pub fn is_async(&self) -> bool {
@@ -65,17 +290,105 @@ impl DataSource {
         }
     }
 
-    pub fn into_async(self) -> Option<Rc<dyn AsyncDataSource>> {
+    pub fn get_range(&self, range: Range<usize>) -> Result<Bytes> {
         match self {
-            Self::Sync(_) => None,
-            Self::Async(inner) => Some(inner),
+            Self::Sync(inner) => inner.get_range(range),
+            Self::Async(inner) => block_on(inner.get_range_async(range)),
         }
     }
 
-    pub fn into_sync(self) -> Option<Rc<RefCell<dyn SyncDataSource>>> {
+    pub async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes> {
         match self {
-            Self::Sync(inner) => Some(inner),
-            Self::Async(inner) => None,
+            Self::Sync(inner) => inner.get_range(range),
+            Self::Async(inner) => inner.get_range_async(range).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::Reader;
+    use crate::test_setup::*;
+
+    #[test]
+    fn test_mapped_file() {
+        setup();
+        let file = new_dummy_path(4, 10).unwrap();
+        let expected = file.reopen().unwrap();
+        let mapped = MappedFile::map(file.reopen().unwrap()).ok().unwrap();
+        assert_eq!(mapped.get_range(3..20).unwrap(), expected.get_range(3..20).unwrap());
+        assert!(mapped.get_range(0..1 << 20).is_err());
+        let (start, end) = (20, 3);
+        assert!(mapped.get_range(start..end).is_err());
+
+        let path = file.path().to_str().unwrap();
+        let samples: Vec<_> = Reader::new()
+            .open_file(path)
+            .unwrap()
+            .iter()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let mapped: Vec<_> = Reader::new()
+            .open_file_mmap(path)
+            .unwrap()
+            .iter()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(mapped, samples);
+    }
+
+    #[test]
+    fn test_threaded_file() {
+        setup();
+        let file = new_dummy_path(16, 10).unwrap();
+        let expected = file.reopen().unwrap();
+        let pool = Arc::new(ReadPool::default());
+        let threaded = ThreadedFile::new(file.reopen().unwrap(), pool.clone());
+        let ranges: Vec<_> = (0..16).map(|i| i * 7..i * 7 + 30).collect();
+        for threads in [0, 4] {
+            pool.grow(threads);
+            let reads = block_on(futures::future::try_join_all(
+                ranges.iter().map(|range| threaded.get_range_async(range.clone())),
+            ))
+            .unwrap();
+            for (range, read) in ranges.iter().zip(reads) {
+                assert_eq!(read, expected.get_range(range.clone()).unwrap());
+            }
+            assert!(block_on(threaded.get_range_async(0..1 << 20)).is_err());
+        }
+        assert_eq!(pool.workers.lock().unwrap().as_ref().unwrap().started, 4);
+
+        // A forked process finds workers started by another pid and starts its own.
+        pool.workers.lock().unwrap().as_mut().unwrap().pid = 0;
+        assert_eq!(
+            block_on(threaded.get_range_async(0..8)).unwrap(),
+            expected.get_range(0..8).unwrap()
+        );
+        let workers = pool.workers.lock().unwrap();
+        assert_eq!(
+            workers.as_ref().map(|workers| (workers.pid, workers.started)),
+            Some((process::id(), 4))
+        );
+    }
+
+    #[test]
+    fn test_file_pool_evicts_least_recently_used() {
+        setup();
+        let files: Vec<_> = (0..3).map(|_| new_dummy_path(4, 10).unwrap()).collect();
+        let pool = Arc::new(Mutex::new(FilePool::default()));
+        pool.lock().unwrap().set_max_open(2);
+        let sources: Vec<_> = files
+            .iter()
+            .map(|file| PooledFile::new(file.path().to_path_buf(), pool.clone(), Arc::new(ReadPool::default())))
+            .collect();
+
+        for idx in [0, 1, 0, 2] {
+            sources[idx].get_range(0..8).unwrap();
         }
+        let open: Vec<_> = pool.lock().unwrap().open.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(open, vec![files[2].path().to_path_buf(), files[0].path().to_path_buf()]);
     }
 }
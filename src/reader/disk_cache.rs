@@ -0,0 +1,422 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use color_eyre::eyre::{ensure, eyre, Result, WrapErr};
+use futures::future::try_join_all;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::header::Header;
+use crate::reader::cache::BlockCache;
+use crate::reader::datasource::{read_exact_at, AsyncDataSource, DataSource};
+
+/// Directory holding chunks of remote archives, kept under `max_bytes` by evicting the least recently used chunks.
+///
+/// Archives are fetched in aligned chunks of `chunk_size` bytes whatever ranges are read, cached chunks are only read
+/// from as much as asked for. Chunks are written to a temporary file and renamed into place, so several processes can
+/// share the directory.
+pub(crate) struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    chunk_size: usize,
+    usage: Mutex<Usage>,
+}
+
+/// The directory is scanned again after this long, to account for the chunks written by other processes.
+const RESCAN_AFTER: Duration = Duration::from_secs(60);
+
+/// Bytes of chunks found by the last scan of the directory, plus the ones this process wrote since.
+#[derive(Default)]
+struct Usage {
+    bytes: u64,
+    scanned: Option<Instant>,
+}
+
+impl DiskCache {
+    /// Fails if `dir` cannot be written to, later writes failing only cost fetching their chunks again.
+    pub(crate) fn new(dir: impl Into<PathBuf>, max_bytes: u64, chunk_size: usize) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).wrap_err_with(|| format!("Failed to create cache directory {}", dir.display()))?;
+        let probe = dir.join(format!(".probe.{}.tmp", process::id()));
+        fs::write(&probe, [])
+            .and_then(|_| fs::remove_file(&probe))
+            .wrap_err_with(|| format!("Failed to write to cache directory {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            max_bytes,
+            chunk_size,
+            usage: Mutex::default(),
+        })
+    }
+
+    /// Directory of the chunks of the archive at `uri`, named by a hash of its location and of its contents.
+    ///
+    /// The query of a URL is left out as it usually holds a signature changing between runs. The contents are told
+    /// apart by the header and by `version`, e.g. an ETag, so an archive rewritten in place is not read from stale
+    /// chunks.
+    fn archive_dir(&self, uri: &str, header: &Header, version: Option<&str>) -> PathBuf {
+        let location = uri.split(['?', '#']).next().unwrap_or(uri);
+        let mut hasher = Sha256::new();
+        hasher.update(location.as_bytes());
+        hasher.update([0]);
+        hasher.update(version.unwrap_or_default().as_bytes());
+        hasher.update([0]);
+        hasher.update(self.chunk_size.to_be_bytes());
+        hasher.update(header.byte_size().to_be_bytes());
+        for (key, entry) in header.entries() {
+            hasher.update(key.as_bytes());
+            hasher.update([0]);
+            hasher.update(entry.start_idx().to_be_bytes());
+            hasher.update(entry.length().to_be_bytes());
+        }
+        self.dir.join(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Reads bytes `range` of a cached chunk of `len` bytes, marking it as used.
+    fn read(&self, path: &Path, len: usize, range: Range<usize>) -> Option<Bytes> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .or_else(|_| File::open(path))
+            .ok()?;
+        if file.metadata().ok()?.len() != len as u64 {
+            return None;
+        }
+        let mut buf = BytesMut::zeroed(range.len());
+        read_exact_at(&file, &mut buf, range.start as u64).ok()?;
+        let _ = file.set_modified(SystemTime::now());
+        Some(buf.freeze())
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let dir = path.parent().ok_or(eyre!("Invalid cache path"))?;
+        fs::create_dir_all(dir)?;
+        let name = path.file_name().ok_or(eyre!("Invalid cache path"))?.to_string_lossy();
+        let tmp = dir.join(format!(
+            ".{name}.{}.{:x}.tmp",
+            process::id(),
+            rand::thread_rng().gen::<u64>()
+        ));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)?;
+
+        let mut usage = self.usage.lock().unwrap();
+        usage.bytes += data.len() as u64;
+        let fresh = usage.scanned.is_some_and(|scanned| scanned.elapsed() <= RESCAN_AFTER);
+        if !fresh || usage.bytes > self.max_bytes {
+            usage.bytes = self.evict()?;
+            usage.scanned = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Removes the least recently used chunks until the cache fits in `max_bytes`, returns the bytes left.
+    fn evict(&self) -> Result<u64> {
+        let mut chunks = Vec::new();
+        for archive in fs::read_dir(&self.dir)? {
+            let archive = archive?.path();
+            if !archive.is_dir() {
+                continue;
+            }
+            for chunk in fs::read_dir(&archive)? {
+                let chunk = chunk?;
+                if chunk.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                // Another process may have evicted it since the directory was listed.
+                if let Ok(metadata) = chunk.metadata() {
+                    chunks.push((metadata.modified()?, metadata.len(), chunk.path()));
+                }
+            }
+        }
+        let mut total: u64 = chunks.iter().map(|(_, len, _)| len).sum();
+        chunks.sort();
+        let mut emptied = HashSet::new();
+        for (_, len, path) in chunks {
+            if total <= self.max_bytes {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => total -= len,
+            }
+            emptied.extend(path.parent().map(Path::to_path_buf));
+        }
+        // Only empty directories are removed, the ones still holding chunks or a chunk being written are kept.
+        for archive in emptied {
+            let _ = fs::remove_dir(archive);
+        }
+        Ok(total)
+    }
+}
+
+/// Fetches whole chunks from the remote source and stores them in the disk cache.
+struct ChunkFetch {
+    inner: DataSource,
+    cache: Arc<DiskCache>,
+    dir: PathBuf,
+}
+
+#[async_trait]
+impl AsyncDataSource for ChunkFetch {
+    async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes> {
+        let data = self.inner.get_range_async(range.clone()).await?;
+        // A full or read-only disk only costs fetching the chunk again.
+        let _ = self
+            .cache
+            .write(&self.dir.join((range.start / self.cache.chunk_size).to_string()), &data);
+        Ok(data)
+    }
+}
+
+/// A remote archive read through a [`DiskCache`], only chunks missing from the cache are fetched.
+pub(crate) struct DiskCachedSource {
+    cache: Arc<DiskCache>,
+    dir: PathBuf,
+    len: usize,
+    /// Only deduplicates concurrent fetches of a chunk within this process, keeps nothing in memory.
+    fetches: BlockCache,
+    fetch: DataSource,
+}
+
+impl DiskCachedSource {
+    /// `uri`, `header` and `version` name the archive in the cache.
+    pub(crate) fn new(
+        inner: DataSource,
+        cache: Arc<DiskCache>,
+        uri: &str,
+        header: &Header,
+        version: Option<&str>,
+    ) -> Self {
+        let dir = cache.archive_dir(uri, header, version);
+        let len = header.archive_size();
+        let fetch = DataSource::new_async(ChunkFetch {
+            inner,
+            cache: cache.clone(),
+            dir: dir.clone(),
+        });
+        Self {
+            cache,
+            dir,
+            len,
+            fetches: BlockCache::new(0),
+            fetch,
+        }
+    }
+
+    /// Bytes `within` of chunk `idx`, the whole chunk is fetched if it is not cached.
+    async fn chunk(&self, idx: usize, within: Range<usize>) -> Result<Bytes> {
+        let size = self.cache.chunk_size;
+        let range = idx * size..self.len.min((idx + 1) * size);
+        match self
+            .cache
+            .read(&self.dir.join(idx.to_string()), range.len(), within.clone())
+        {
+            Some(data) => Ok(data),
+            None => Ok(self.fetches.get((0, range), &self.fetch).await?.slice(within)),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncDataSource for DiskCachedSource {
+    async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes> {
+        ensure!(
+            range.start <= range.end && range.end <= self.len,
+            eyre!("Range {range:?} out of bounds for archive of length {}", self.len)
+        );
+        if range.is_empty() {
+            return Ok(Bytes::new());
+        }
+        let size = self.cache.chunk_size;
+        let chunks = (range.start / size..=(range.end - 1) / size).map(|idx| {
+            let offset = idx * size;
+            self.chunk(
+                idx,
+                range.start.max(offset) - offset..range.end.min(offset + size) - offset,
+            )
+        });
+        let mut chunks = try_join_all(chunks).await?;
+        Ok(match chunks.len() {
+            1 => chunks.pop().unwrap(),
+            _ => chunks.concat().into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::executor::block_on;
+    use futures::future::join_all;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::header::SampleMD;
+    use crate::reader::datasource::SyncDataSource;
+    use crate::test_setup::*;
+
+    const CHUNK_SIZE: usize = 4 << 10;
+
+    struct Remote {
+        data: Bytes,
+        fetched: AtomicUsize,
+    }
+
+    impl SyncDataSource for Remote {
+        fn get_range(&self, range: Range<usize>) -> Result<Bytes> {
+            self.fetched.fetch_add(range.len(), Ordering::SeqCst);
+            Ok(self.data.slice(range))
+        }
+    }
+
+    /// Length of the archive, three whole chunks and a partial one.
+    const LEN: usize = 3 * CHUNK_SIZE + 100;
+
+    fn remote() -> Arc<Remote> {
+        Arc::new(Remote {
+            data: (0..LEN).map(|i| (i % 251) as u8).collect::<Vec<_>>().into(),
+            fetched: AtomicUsize::new(0),
+        })
+    }
+
+    fn header(key: &str) -> Header {
+        let mut header = Header::new(92).unwrap();
+        header.insert(key, SampleMD::new(0, LEN - 100).unwrap()).unwrap();
+        header
+    }
+
+    fn open(dir: &TempDir, remote: &Arc<Remote>, uri: &str, max_bytes: u64) -> DiskCachedSource {
+        open_version(dir, remote, uri, max_bytes, &header("data"), None)
+    }
+
+    fn open_version(
+        dir: &TempDir,
+        remote: &Arc<Remote>,
+        uri: &str,
+        max_bytes: u64,
+        header: &Header,
+        version: Option<&str>,
+    ) -> DiskCachedSource {
+        let cache = Arc::new(DiskCache::new(dir.path(), max_bytes, CHUNK_SIZE).unwrap());
+        DiskCachedSource::new(DataSource::Sync(remote.clone()), cache, uri, header, version)
+    }
+
+    fn fetched(remote: &Remote) -> usize {
+        remote.fetched.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn test_archive_location() {
+        setup();
+        let (dir, remote) = (TempDir::new().unwrap(), remote());
+        let first = open(&dir, &remote, "gs://bucket/dummy.raa", 1 << 30);
+        // A signature in the query does not change the archive, other contents of the same length do.
+        let signed = open(&dir, &remote, "gs://bucket/dummy.raa?X-Goog-Signature=abc", 1 << 30);
+        assert_eq!(signed.dir, first.dir);
+        let other = open_version(&dir, &remote, "gs://bucket/dummy.raa", 1 << 30, &header("other"), None);
+        assert_ne!(other.dir, first.dir);
+        let rewritten = open_version(
+            &dir,
+            &remote,
+            "gs://bucket/dummy.raa",
+            1 << 30,
+            &header("data"),
+            Some("\"2\""),
+        );
+        assert_ne!(rewritten.dir, first.dir);
+
+        let uri = format!("https://example.com/{}.raa", "a".repeat(1000));
+        let long = open(&dir, &remote, &uri, 1 << 30);
+        assert_eq!(long.get_range(10..20).unwrap(), remote.data.slice(10..20));
+        assert!(long.cache.read(&long.dir.join("0"), CHUNK_SIZE, 0..1).is_some());
+    }
+
+    #[test]
+    fn test_chunked_reads() {
+        setup();
+        let (dir, remote) = (TempDir::new().unwrap(), remote());
+        let first = open(&dir, &remote, "gs://bucket/dummy.raa", 1 << 30);
+        for range in [10..20, CHUNK_SIZE - 5..CHUNK_SIZE + 5, 3 * CHUNK_SIZE..LEN, 15..25] {
+            assert_eq!(first.get_range(range.clone()).unwrap(), remote.data.slice(range));
+        }
+        // Whole chunks are fetched once, cached ones are read from disk.
+        assert_eq!(fetched(&remote), 2 * CHUNK_SIZE + 100);
+        assert!(first.get_range(0..LEN + 1).is_err());
+
+        // Another process opening the same archive reads what the first one fetched.
+        let second = open(&dir, &remote, "gs://bucket/dummy.raa", 1 << 30);
+        assert_eq!(second.get_range(0..LEN).unwrap(), remote.data);
+        assert_eq!(fetched(&remote), 3 * CHUNK_SIZE + 100);
+    }
+
+    #[test]
+    fn test_concurrent_fetches() {
+        setup();
+        let (dir, remote) = (TempDir::new().unwrap(), remote());
+        let source = open(&dir, &remote, "gs://bucket/dummy.raa", 1 << 30);
+        let ranges = [10..20, 15..25, CHUNK_SIZE - 5..CHUNK_SIZE + 5];
+        let reads = block_on(join_all(
+            ranges.iter().map(|range| source.get_range_async(range.clone())),
+        ));
+        for (range, read) in ranges.iter().zip(reads) {
+            assert_eq!(read.unwrap(), remote.data.slice(range.clone()));
+        }
+        assert_eq!(fetched(&remote), 2 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_eviction() {
+        setup();
+        let (dir, remote) = (TempDir::new().unwrap(), remote());
+        let evicted = open(&dir, &remote, "gs://bucket/dummy.raa", 1 << 30);
+        assert_eq!(evicted.get_range(0..10).unwrap(), remote.data.slice(0..10));
+
+        let small = open(&dir, &remote, "gs://bucket/other.raa", 2 * CHUNK_SIZE as u64);
+        assert_eq!(small.get_range(0..LEN).unwrap(), remote.data);
+        let cached: u64 = fs::read_dir(dir.path())
+            .unwrap()
+            .flat_map(|archive| fs::read_dir(archive.unwrap().path()).unwrap())
+            .map(|chunk| chunk.unwrap().metadata().unwrap().len())
+            .sum();
+        assert!(cached <= 2 * CHUNK_SIZE as u64);
+        // The chunks read last are the ones kept, the directory of an archive left without chunks is removed.
+        assert_eq!(
+            small.cache.read(&small.dir.join("3"), 100, 10..20).unwrap(),
+            remote.data.slice(3 * CHUNK_SIZE + 10..3 * CHUNK_SIZE + 20)
+        );
+        assert!(!evicted.dir.exists());
+    }
+
+    #[test]
+    fn test_eviction_scans() {
+        setup();
+        let dir = TempDir::new().unwrap();
+        let cache = DiskCache::new(dir.path(), 3 * CHUNK_SIZE as u64, CHUNK_SIZE).unwrap();
+        let chunk = vec![0u8; CHUNK_SIZE];
+        cache.write(&dir.path().join("archive").join("0"), &chunk).unwrap();
+
+        // Chunks written by another process are only seen by the next scan, which the size written since triggers.
+        let other = dir.path().join("other");
+        fs::create_dir_all(&other).unwrap();
+        fs::write(other.join("0"), &chunk).unwrap();
+        fs::write(other.join("1"), &chunk).unwrap();
+        cache.write(&dir.path().join("archive").join("1"), &chunk).unwrap();
+        assert_eq!(cache.usage.lock().unwrap().bytes, 2 * CHUNK_SIZE as u64);
+        cache.write(&dir.path().join("archive").join("2"), &chunk).unwrap();
+        assert_eq!(cache.usage.lock().unwrap().bytes, 3 * CHUNK_SIZE as u64);
+        cache.write(&dir.path().join("archive").join("3"), &chunk).unwrap();
+        assert!(cache.usage.lock().unwrap().bytes <= 3 * CHUNK_SIZE as u64);
+        assert!(!other.join("0").exists());
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::ops::{Range, RangeInclusive};
+use std::sync::Arc;
+
+use regex::Regex;
+
+use crate::header::{Header, SampleMD};
+
+pub(crate) type Predicate = Arc<dyn Fn(&str, &SampleMD) -> bool + Send + Sync>;
+
+/// Condition a sample must meet to be part of the blocks a reader collects.
+#[derive(Clone)]
+pub(crate) enum Filter {
+    Prefix(String),
+    Regex(Regex),
+    Allow(Arc<HashSet<String>>),
+    Deny(Arc<HashSet<String>>),
+    Size(RangeInclusive<usize>),
+    Predicate(Predicate),
+}
+
+impl Debug for Filter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Filter::Prefix(prefix) => write!(f, "Prefix({prefix:?})"),
+            Filter::Regex(regex) => write!(f, "Regex({:?})", regex.as_str()),
+            Filter::Allow(keys) => write!(f, "Allow({} keys)", keys.len()),
+            Filter::Deny(keys) => write!(f, "Deny({} keys)", keys.len()),
+            Filter::Size(range) => write!(f, "Size({range:?})"),
+            Filter::Predicate(_) => write!(f, "Predicate"),
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, key: &str, entry: &SampleMD) -> bool {
+        match self {
+            Filter::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            Filter::Regex(regex) => regex.is_match(key),
+            Filter::Allow(keys) => keys.contains(key),
+            Filter::Deny(keys) => !keys.contains(key),
+            Filter::Size(range) => range.contains(&entry.length()),
+            Filter::Predicate(predicate) => predicate(key, entry),
+        }
+    }
+}
+
+/// Runs of consecutive header positions whose samples pass every filter.
+pub(crate) fn matching_runs(header: &Header, filters: &[Filter]) -> Vec<Range<usize>> {
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for (idx, (key, entry)) in header.entries().iter().enumerate() {
+        if !filters.iter().all(|filter| filter.matches(key, entry)) {
+            continue;
+        }
+        match runs.last_mut() {
+            Some(run) if run.end == idx => run.end += 1,
+            _ => runs.push(idx..idx + 1),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        let mut header = Header::new(1024).unwrap();
+        for i in 0..10 {
+            let key = format!("{}/{i}", if i < 5 { "train" } else { "test" });
+            header.insert(&key, SampleMD::new(i * 10, i + 1).unwrap()).unwrap();
+        }
+        header
+    }
+
+    #[test]
+    fn test_matching_runs() {
+        let header = header();
+        assert_eq!(matching_runs(&header, &[]), vec![0..10]);
+        assert_eq!(matching_runs(&header, &[Filter::Prefix("test/".into())]), vec![5..10]);
+        let regex = Filter::Regex(Regex::new(r"/[02468]$").unwrap());
+        assert_eq!(matching_runs(&header, &[regex]), vec![0..1, 2..3, 4..5, 6..7, 8..9]);
+
+        let deny = Filter::Deny(Arc::new(["train/1".to_string(), "train/2".to_string()].into()));
+        assert_eq!(matching_runs(&header, &[deny, Filter::Size(0..=4)]), vec![0..1, 3..4]);
+        let predicate = Filter::Predicate(Arc::new(|_, entry| entry.start_idx() >= 70));
+        assert_eq!(matching_runs(&header, &[predicate]), vec![7..10]);
+    }
+}
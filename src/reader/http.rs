@@ -0,0 +1,288 @@
+use std::io::{Cursor, Read};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::{ensure, Result, WrapErr};
+use futures::channel::oneshot;
+use thiserror::Error;
+use ureq::{Agent, AgentBuilder};
+
+use crate::header::Header;
+use crate::reader::datasource::{AsyncDataSource, ReadPool};
+
+#[derive(Error, Debug)]
+pub enum HttpError {
+    #[error("Expected a partial response to a range request of {0}, got status {1}")]
+    NotPartial(String, u16),
+    #[error("Invalid Content-Range {0:?} in response to a request of bytes {1:?}")]
+    InvalidContentRange(String, Range<usize>),
+    #[error("Got {0} bytes of {1}, expected bytes {2:?}")]
+    ShortRead(usize, String, Range<usize>),
+}
+
+/// How the header of an archive served over HTTP is fetched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderFetch {
+    /// Fetches the size of the header, then exactly the header.
+    Exact,
+    /// Fetches this many bytes at once, only fetching the rest of the header if it is longer.
+    Prefetch(usize),
+}
+
+impl Default for HeaderFetch {
+    /// Enough for the default header size in one request.
+    fn default() -> Self {
+        Self::Prefetch((1 << 20) + 8)
+    }
+}
+
+/// Client shared by the archives of a reader, keeping connections to every host open between requests.
+#[derive(Clone)]
+pub(crate) struct HttpClient {
+    agent: Agent,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        let agent = AgentBuilder::new()
+            .max_idle_connections_per_host(32)
+            .timeout_connect(Duration::from_secs(30))
+            .timeout_read(Duration::from_secs(60))
+            .build();
+        Self { agent }
+    }
+}
+
+/// Adds the headers authenticating a range request, e.g. to an object store.
+pub(crate) trait SignRequest: Send + Sync {
+    /// Headers to send along with a GET of `url` for the bytes in `range`, the value of its Range header.
+    fn headers(&self, url: &str, range: &str) -> Result<Vec<(String, String)>>;
+}
+
+/// An archive served over HTTP(S) by any server supporting range requests, read on a [`ReadPool`].
+#[derive(Clone)]
+pub(crate) struct HttpSource {
+    agent: Agent,
+    url: String,
+    pool: Arc<ReadPool>,
+    signer: Option<Arc<dyn SignRequest>>,
+    timeout: Option<Duration>,
+}
+
+impl HttpSource {
+    pub(crate) fn new(client: &HttpClient, url: &str, pool: Arc<ReadPool>) -> Self {
+        Self {
+            agent: client.agent.clone(),
+            url: url.to_string(),
+            pool,
+            signer: None,
+            timeout: None,
+        }
+    }
+
+    /// Gives up on a request after `timeout`, so a stalled one does not keep holding a thread of the pool.
+    pub(crate) fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    #[cfg(feature = "s3")]
+    pub(crate) fn with_signer(mut self, signer: Arc<dyn SignRequest>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Bytes `range` of the archive, fewer if it ends before `range` does.
+    fn fetch(&self, range: Range<usize>) -> Result<Bytes> {
+        self.fetch_versioned(range).map(|(data, _)| data)
+    }
+
+    /// Bytes `range` of the archive and its ETag, if the server sent one.
+    fn fetch_versioned(&self, range: Range<usize>) -> Result<(Bytes, Option<String>)> {
+        if range.is_empty() {
+            return Ok((Bytes::new(), None));
+        }
+        let url = &self.url;
+        let bytes = format!("bytes={}-{}", range.start, range.end - 1);
+        let mut request = self.agent.get(url).set("Range", &bytes);
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        if let Some(signer) = &self.signer {
+            for (name, value) in signer.headers(url, &bytes)? {
+                request = request.set(&name, &value);
+            }
+        }
+        let response = request
+            .call()
+            .wrap_err_with(|| format!("Failed to read bytes {range:?} of {url}"))?;
+        ensure!(
+            response.status() == 206,
+            HttpError::NotPartial(url.to_string(), response.status())
+        );
+        let etag = response.header("ETag").map(str::to_string);
+        let content_range = response.header("Content-Range").unwrap_or_default().to_string();
+        let served = parse_content_range(&content_range)
+            .filter(|served| served.start == range.start && served.end <= range.end)
+            .ok_or(HttpError::InvalidContentRange(content_range, range.clone()))?;
+
+        let mut data = Vec::with_capacity(served.len());
+        response
+            .into_reader()
+            .take(served.len() as u64)
+            .read_to_end(&mut data)?;
+        ensure!(
+            data.len() == served.len(),
+            HttpError::ShortRead(data.len(), url.to_string(), served)
+        );
+        Ok((data.into(), etag))
+    }
+
+    /// The header of the archive and its ETag, which tells its contents apart from what was there before.
+    pub(crate) fn read_header(&self, fetch: HeaderFetch) -> Result<(Header, Option<String>)> {
+        let prefetch = match fetch {
+            HeaderFetch::Exact => 8,
+            HeaderFetch::Prefetch(len) => len.max(8),
+        };
+        let (data, etag) = self.fetch_versioned(0..prefetch)?;
+        let mut data = data.to_vec();
+        if let Some(max_size) = data.get(..8) {
+            let end = 8 + u64::from_be_bytes(max_size.try_into()?) as usize;
+            if data.len() < end {
+                data.extend(self.fetch(data.len()..end)?);
+            }
+        }
+        let header =
+            Header::read(&mut Cursor::new(data)).wrap_err_with(|| format!("Failed to read header of {}", self.url))?;
+        Ok((header, etag))
+    }
+}
+
+/// First and last byte of a `bytes first-last/total` header, as a range.
+fn parse_content_range(value: &str) -> Option<Range<usize>> {
+    let (first, last) = value.strip_prefix("bytes ")?.split_once('/')?.0.split_once('-')?;
+    let (first, last): (usize, usize) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+    (first <= last).then_some(first..last + 1)
+}
+
+#[async_trait]
+impl AsyncDataSource for HttpSource {
+    async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes> {
+        let (sender, receiver) = oneshot::channel();
+        let source = self.clone();
+        self.pool.spawn(Box::new(move || {
+            let read = source.fetch(range.clone()).and_then(|data| {
+                ensure!(
+                    data.len() == range.len(),
+                    HttpError::ShortRead(data.len(), source.url, range)
+                );
+                Ok(data)
+            });
+            let _ = sender.send(read);
+        }));
+        receiver.await.wrap_err("Read thread stopped")?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+
+    use tiny_http::{Response, Server};
+
+    use super::*;
+    use crate::reader::datasource::SyncDataSource;
+    use crate::reader::{Reader, RetryPolicy};
+    use crate::test_setup::*;
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Serve {
+        Ranges,
+        Whole,
+        WrongRange,
+        Stall,
+    }
+
+    /// Serves `data` on a local port, answering range requests as told.
+    fn serve(data: Vec<u8>, serve: Serve) -> String {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/dummy.raa", server.server_addr().to_ip().unwrap());
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let range = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Range"))
+                    .and_then(|header| header.value.as_str().strip_prefix("bytes="))
+                    .and_then(|range| range.split_once('-'))
+                    .map(|(first, last)| (first.parse::<usize>().unwrap(), last.parse::<usize>().unwrap()));
+                if serve == Serve::Stall {
+                    thread::sleep(Duration::from_secs(5));
+                }
+                let response = match (range, serve) {
+                    (Some((first, last)), Serve::Ranges | Serve::WrongRange) => {
+                        let last = last.min(data.len() - 1);
+                        let shift = if serve == Serve::WrongRange { 1 } else { 0 };
+                        let content_range = format!("bytes {}-{}/{}", first + shift, last, data.len());
+                        Response::from_data(data[first..=last].to_vec())
+                            .with_status_code(206)
+                            .with_header(tiny_http::Header::from_bytes("Content-Range", content_range).unwrap())
+                    }
+                    _ => Response::from_data(data.clone()),
+                };
+                let _ = request.respond(response);
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_http_source() {
+        setup();
+        let file = new_dummy_path(64, 100).unwrap();
+        let data = std::fs::read(file.path()).unwrap();
+        let mut reader = Reader::new();
+        reader.open_file(file.path().to_str().unwrap()).unwrap().by_count(4);
+        let expected: Vec<_> = reader.iter().unwrap().map(Result::unwrap).collect();
+
+        let url = serve(data.clone(), Serve::Ranges);
+        for fetch in [HeaderFetch::Exact, HeaderFetch::Prefetch(64), HeaderFetch::default()] {
+            reader.open_http(&url, fetch).unwrap().with_buffering(8).unwrap();
+            assert_eq!(reader.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), expected);
+        }
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache = dir.path().to_str().unwrap();
+        reader.with_disk_cache(cache, 1 << 30, 1000).unwrap();
+        assert_eq!(reader.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), expected);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert_eq!(reader.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), expected);
+        assert!(reader.with_disk_cache(cache, 1 << 30, 0).is_err());
+        let source = HttpSource::new(&HttpClient::default(), &url, Arc::new(ReadPool::default()));
+        assert!(source.get_range(data.len() - 10..data.len() + 10).is_err());
+
+        let whole = serve(data.clone(), Serve::Whole);
+        let err = reader.open_http(&whole, HeaderFetch::Exact).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(HttpError::NotPartial(_, 200))));
+        let wrong = serve(data, Serve::WrongRange);
+        let err = reader.open_http(&wrong, HeaderFetch::Exact).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(HttpError::InvalidContentRange(..))));
+        assert_eq!(parse_content_range("bytes 0-99/*"), Some(0..100));
+
+        // The timeout of the retry policy is passed down to the requests, so a stalled one gives up its thread.
+        let stalled = serve(Vec::new(), Serve::Stall);
+        let mut policy = RetryPolicy::new(0);
+        policy.with_timeout(Duration::from_millis(100));
+        let start = Instant::now();
+        let err = reader
+            .with_retries(&policy)
+            .unwrap()
+            .open_http(&stalled, HeaderFetch::Exact);
+        assert!(err.is_err());
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert_eq!(parse_content_range("bytes */100"), None);
+    }
+}
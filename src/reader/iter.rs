@@ -0,0 +1,164 @@
+use std::vec::IntoIter;
+
+use bincode::Options;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use crate::reader::batch::BatchIter;
+use crate::reader::block::{SampleId, YieldedId};
+use crate::reader::collector::Collector;
+use crate::reader::shuffle::{BufferState, ShuffleBuffer};
+use crate::reader::Sample;
+
+/// Position of a [`SampleIter`], enough to rebuild it and continue with the next sample.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReaderState {
+    pub(crate) seed: Option<u64>,
+    pub(crate) epoch: u64,
+    pub(crate) shard: Vec<(u16, u16)>,
+    pub(crate) worker: Option<(u16, u16)>,
+    pub(crate) block: usize,
+    pub(crate) sample: usize,
+    pub(crate) buffer: Option<BufferState>,
+    /// Fingerprint of the block plan the positions refer to.
+    pub(crate) plan: u64,
+}
+
+impl ReaderState {
+    fn get_options() -> impl Options {
+        bincode::DefaultOptions::new().with_varint_encoding().with_big_endian()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Self::get_options()
+            .serialize(self)
+            .map_err(|e| eyre!(e))
+            .wrap_err("Failed to write reader state")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::get_options()
+            .deserialize(bytes)
+            .map_err(|e| eyre!(e))
+            .wrap_err("Failed to read reader state")
+    }
+}
+
+/// Epoch of a block and its samples, or the error reading it.
+pub(crate) type ReadBlock = (u64, Result<Vec<(SampleId, Sample)>>);
+
+/// Flattens read blocks into indexed samples, keeping track of how far into the block order it is.
+///
+/// A block that failed counts as started but not consumed, so resuming right after the error reads it again.
+pub(crate) struct BlockSamples {
+    blocks: Box<dyn Iterator<Item = ReadBlock> + Send>,
+    current: IntoIter<(SampleId, Sample)>,
+    epoch: u64,
+    block: usize,
+    sample: usize,
+    skip: usize,
+}
+
+impl BlockSamples {
+    /// `blocks` must already skip the blocks preceding the one the cursor `(epoch, block, sample)` points into.
+    pub(crate) fn new(
+        blocks: Box<dyn Iterator<Item = ReadBlock> + Send>,
+        epoch: u64,
+        block: usize,
+        sample: usize,
+    ) -> Self {
+        Self {
+            blocks,
+            current: Vec::new().into_iter(),
+            epoch,
+            block: block.saturating_sub(1),
+            sample: 0,
+            skip: sample,
+        }
+    }
+}
+
+impl Iterator for BlockSamples {
+    type Item = Result<(YieldedId, Sample)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((id, sample)) = self.current.next() {
+                self.sample += 1;
+                return Some(Ok(((self.epoch, id), sample)));
+            }
+            let (epoch, samples) = self.blocks.next()?;
+            if epoch != self.epoch {
+                self.epoch = epoch;
+                self.block = 0;
+            }
+            self.block += 1;
+            self.sample = 0;
+            let mut samples = match samples {
+                Ok(samples) => samples,
+                Err(err) => {
+                    self.skip = 0;
+                    return Some(Err(err));
+                }
+            };
+            self.sample = self.skip.min(samples.len());
+            self.current = samples.split_off(self.sample).into_iter();
+            self.skip = 0;
+        }
+    }
+}
+
+pub struct SampleIter {
+    collector: Collector,
+    samples: BlockSamples,
+    buffer: Option<ShuffleBuffer>,
+    plan: u64,
+}
+
+impl SampleIter {
+    pub(crate) fn new(collector: Collector, samples: BlockSamples, buffer: Option<ShuffleBuffer>, plan: u64) -> Self {
+        Self {
+            collector,
+            samples,
+            buffer,
+            plan,
+        }
+    }
+
+    /// Groups the remaining samples into batches of `batch_size`, the last smaller one is dropped if `drop_last`.
+    pub fn batched(self, batch_size: usize, drop_last: bool) -> Result<BatchIter> {
+        BatchIter::new(self, batch_size, drop_last)
+    }
+
+    /// Epoch blocks are currently read from, samples still in the shuffle buffer may belong to the previous one.
+    pub fn epoch(&self) -> u64 {
+        self.samples.epoch
+    }
+
+    pub fn state(&self) -> ReaderState {
+        let buffer = self.buffer.as_ref().map(|buffer| buffer.state());
+        let (epoch, block, sample) = (self.samples.epoch, self.samples.block, self.samples.sample);
+        self.collector.state(epoch, block, sample, buffer, self.plan)
+    }
+}
+
+impl SampleIter {
+    pub(crate) fn next_with_id(&mut self) -> Option<Result<(YieldedId, Sample)>> {
+        match &mut self.buffer {
+            Some(buffer) => buffer.next(&mut self.samples),
+            None => self.samples.next(),
+        }
+    }
+}
+
+impl Iterator for SampleIter {
+    type Item = Result<Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_id().map(|item| item.map(|(_, sample)| sample))
+    }
+}
@@ -0,0 +1,304 @@
+use std::str::FromStr;
+
+use bincode::Options;
+use color_eyre::eyre::{bail, ensure, eyre, Error, Result, WrapErr};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::reader::iter::{ReaderState, SampleIter};
+use crate::reader::{Reader, Sample};
+
+#[derive(Error, Debug)]
+pub enum MixerError {
+    #[error("Weights must be positive and finite, got: {0}")]
+    InvalidWeight(f64),
+    #[error("Mixer has no sources")]
+    NoSources,
+    #[error("Mixer state was saved with {0} sources, mixer has {1}")]
+    StateMismatch(usize, usize),
+    #[error("Unknown mix mode: {0}, expected one of exhaust, repeat")]
+    InvalidMixMode(String),
+}
+
+/// What happens when one of the mixed readers runs out of samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MixMode {
+    /// The reader is dropped from the mixture, which ends once every reader is exhausted.
+    #[default]
+    Exhaust,
+    /// The reader starts over with its next epoch, the mixture ends once every reader made a full pass.
+    Repeat,
+}
+
+impl FromStr for MixMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "exhaust" => Ok(Self::Exhaust),
+            "repeat" => Ok(Self::Repeat),
+            _ => bail!(MixerError::InvalidMixMode(s.to_string())),
+        }
+    }
+}
+
+/// Position of a [`MixIter`], made of the state of every reader that is not exhausted yet.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MixState {
+    rng: ChaCha12Rng,
+    passes: Vec<u64>,
+    sources: Vec<Option<ReaderState>>,
+}
+
+impl MixState {
+    fn get_options() -> impl Options {
+        bincode::DefaultOptions::new().with_varint_encoding().with_big_endian()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Self::get_options()
+            .serialize(self)
+            .map_err(|e| eyre!(e))
+            .wrap_err("Failed to write mixer state")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::get_options()
+            .deserialize(bytes)
+            .map_err(|e| eyre!(e))
+            .wrap_err("Failed to read mixer state")
+    }
+}
+
+/// Interleaves samples of several readers, picking the reader of every sample at random in proportion to its weight.
+///
+/// The picks only depend on the seed, so ranks sharding every reader the same way see the same proportions.
+#[derive(Clone, Default)]
+pub struct Mixer {
+    sources: Vec<(Reader, f64)>,
+    seed: u64,
+    mode: MixMode,
+}
+
+impl Mixer {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            ..Self::default()
+        }
+    }
+
+    pub fn add(&mut self, reader: Reader, weight: f64) -> Result<&mut Self> {
+        ensure!(weight > 0.0 && weight.is_finite(), MixerError::InvalidWeight(weight));
+        self.sources.push((reader, weight));
+        Ok(self)
+    }
+
+    pub fn with_mode(&mut self, mode: MixMode) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_sharding(&mut self, rank: u16, world_size: u16) -> Result<&mut Self> {
+        for (reader, _) in self.sources.iter_mut() {
+            reader.with_sharding(rank, world_size)?;
+        }
+        Ok(self)
+    }
+
+    pub fn with_worker_sharding(&mut self, worker: u16, num_workers: u16) -> Result<&mut Self> {
+        for (reader, _) in self.sources.iter_mut() {
+            reader.with_worker_sharding(worker, num_workers)?;
+        }
+        Ok(self)
+    }
+
+    pub fn iter(&self) -> Result<MixIter> {
+        ensure!(!self.sources.is_empty(), MixerError::NoSources);
+        let iters = self
+            .sources
+            .iter()
+            .map(|(reader, _)| reader.iter().map(Some))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self.build(
+            iters,
+            vec![0; self.sources.len()],
+            ChaCha12Rng::seed_from_u64(self.seed),
+        ))
+    }
+
+    pub fn iter_from(&self, state: &MixState) -> Result<MixIter> {
+        ensure!(!self.sources.is_empty(), MixerError::NoSources);
+        ensure!(
+            state.sources.len() == self.sources.len() && state.passes.len() == self.sources.len(),
+            MixerError::StateMismatch(state.sources.len(), self.sources.len())
+        );
+        let iters = self
+            .sources
+            .iter()
+            .zip(state.sources.iter())
+            .map(|((reader, _), source)| source.as_ref().map(|source| reader.iter_from(source)).transpose())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self.build(iters, state.passes.clone(), state.rng.clone()))
+    }
+
+    fn build(&self, iters: Vec<Option<SampleIter>>, passes: Vec<u64>, rng: ChaCha12Rng) -> MixIter {
+        MixIter {
+            readers: self.sources.iter().map(|(reader, _)| reader.clone()).collect(),
+            weights: self.sources.iter().map(|(_, weight)| *weight).collect(),
+            mode: self.mode,
+            iters,
+            passes,
+            rng,
+        }
+    }
+}
+
+pub struct MixIter {
+    readers: Vec<Reader>,
+    weights: Vec<f64>,
+    mode: MixMode,
+    iters: Vec<Option<SampleIter>>,
+    passes: Vec<u64>,
+    rng: ChaCha12Rng,
+}
+
+impl MixIter {
+    pub fn state(&self) -> MixState {
+        MixState {
+            rng: self.rng.clone(),
+            passes: self.passes.clone(),
+            sources: self
+                .iters
+                .iter()
+                .map(|iter| iter.as_ref().map(|iter| iter.state()))
+                .collect(),
+        }
+    }
+
+    /// Index of the reader the next sample is taken from, `None` once all of them are exhausted.
+    fn pick(&mut self) -> Option<usize> {
+        let active: Vec<usize> = (0..self.iters.len()).filter(|idx| self.iters[*idx].is_some()).collect();
+        let weights = active.iter().map(|idx| self.weights[*idx]);
+        let dist = WeightedIndex::new(weights).ok()?;
+        Some(active[dist.sample(&mut self.rng)])
+    }
+
+    fn finish_pass(&mut self, idx: usize) -> Result<()> {
+        self.passes[idx] += 1;
+        match self.mode {
+            MixMode::Repeat if self.passes.contains(&0) => {
+                let mut reader = self.readers[idx].clone();
+                let epoch = reader.epoch() + self.passes[idx];
+                self.iters[idx] = Some(reader.set_epoch(epoch).iter()?);
+            }
+            MixMode::Repeat => self.iters.iter_mut().for_each(|iter| *iter = None),
+            MixMode::Exhaust => self.iters[idx] = None,
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for MixIter {
+    type Item = Result<Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let idx = self.pick()?;
+            match self.iters[idx].as_mut().unwrap().next() {
+                Some(item) => return Some(item),
+                None => {
+                    if let Err(err) = self.finish_pass(idx) {
+                        return Some(Err(err));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_setup::*;
+
+    fn reader(file: &tempfile::NamedTempFile) -> Reader {
+        let mut reader = Reader::new();
+        reader
+            .open_file(file.path().to_str().unwrap())
+            .unwrap()
+            .by_count(4)
+            .with_shuffling(Some(1));
+        reader
+    }
+
+    /// Samples taken from each reader, which are told apart by the length of their values.
+    fn counts(samples: &[Sample], lens: &[usize]) -> Vec<usize> {
+        let mut counts = vec![0; lens.len()];
+        for (_, value) in samples {
+            counts[lens.iter().position(|len| *len == value.len()).unwrap()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_mixer_exhaust() {
+        setup();
+        let big = new_dummy_path(300, 10).unwrap();
+        let small = new_dummy_path(100, 20).unwrap();
+        let mut mixer = Mixer::new(0);
+        mixer.add(reader(&big), 3.0).unwrap().add(reader(&small), 1.0).unwrap();
+
+        let samples: Vec<Sample> = mixer.iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(counts(&samples, &[10, 20]), vec![300, 100]);
+        // Both readers run out at about the same time when weighted by their size.
+        let first_half = counts(&samples[..200], &[10, 20]);
+        assert!((130..170).contains(&first_half[0]), "{first_half:?}");
+        assert_eq!(samples, mixer.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_mixer_repeat() {
+        setup();
+        let big = new_dummy_path(300, 10).unwrap();
+        let small = new_dummy_path(20, 20).unwrap();
+        let mut mixer = Mixer::new(0);
+        mixer
+            .add(reader(&big), 1.0)
+            .unwrap()
+            .add(reader(&small), 1.0)
+            .unwrap()
+            .with_mode(MixMode::Repeat);
+
+        let samples: Vec<Sample> = mixer.iter().unwrap().map(Result::unwrap).collect();
+        let counts = counts(&samples, &[10, 20]);
+        assert_eq!(counts[0], 300);
+        assert!(counts[1] > 200, "{counts:?}");
+    }
+
+    #[test]
+    fn test_mixer_resume() {
+        setup();
+        let big = new_dummy_path(60, 10).unwrap();
+        let small = new_dummy_path(10, 20).unwrap();
+        let mut mixer = Mixer::new(3);
+        mixer
+            .add(reader(&big), 1.0)
+            .unwrap()
+            .add(reader(&small), 2.0)
+            .unwrap()
+            .with_mode(MixMode::Repeat);
+        let expected: Vec<Sample> = mixer.iter().unwrap().map(Result::unwrap).collect();
+
+        for taken in [0, 7, 25, 50] {
+            let mut iter = mixer.iter().unwrap();
+            let mut samples: Vec<Sample> = iter.by_ref().take(taken).map(Result::unwrap).collect();
+            let state = MixState::from_bytes(&iter.state().to_bytes().unwrap()).unwrap();
+            samples.extend(mixer.iter_from(&state).unwrap().map(Result::unwrap));
+            assert_eq!(samples, expected);
+        }
+    }
+}
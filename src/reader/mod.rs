@@ -1,7 +1,41 @@
-pub use readers::{Reader, Sample};
+pub use batch::{Batch, BatchError, BatchIter};
+pub use bucket::{BucketIter, BucketState, Bucketing};
+pub use dataset::{Dataset, DatasetError};
+#[cfg(feature = "http")]
+pub use http::{HeaderFetch, HttpError};
+pub use iter::{ReaderState, SampleIter};
+pub use mixer::{MixIter, MixMode, MixState, Mixer, MixerError};
+pub use pack::{PackError, PackIter, PackState, Packing, Sequence, TokenWidth};
+pub use policy::{ErrorPolicy, ReadError};
+pub use readers::{Reader, ReaderError, Sample};
+pub use retry::{RetryError, RetryPolicy};
+#[cfg(feature = "s3")]
+pub use s3::{S3Config, S3Error};
+pub use sampling::SamplingError;
+pub use shard::ShardMode;
 
+mod batch;
 mod block;
 mod bounded;
+mod bucket;
+mod cache;
+mod coalesce;
 mod collector;
+mod dataset;
 mod datasource;
+#[cfg(any(feature = "http", feature = "gcs"))]
+mod disk_cache;
+mod filter;
+#[cfg(feature = "http")]
+mod http;
+mod iter;
+mod mixer;
+mod pack;
+mod policy;
 pub mod readers;
+mod retry;
+#[cfg(feature = "s3")]
+mod s3;
+mod sampling;
+mod shard;
+mod shuffle;
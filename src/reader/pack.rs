@@ -0,0 +1,377 @@
+use std::mem;
+use std::str::FromStr;
+
+use bincode::Options;
+use bytes::{Bytes, BytesMut};
+use color_eyre::eyre::{bail, ensure, eyre, Error, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::reader::block::YieldedId;
+use crate::reader::iter::{ReaderState, SampleIter};
+
+#[derive(Error, Debug)]
+pub enum PackError {
+    #[error("Sequence length must be greater than 0")]
+    InvalidSequenceLength,
+    #[error("Separator token {0} does not fit in {1} bytes")]
+    InvalidSeparator(u32, usize),
+    #[error("Sample of {0} bytes is not a whole number of {1} byte tokens")]
+    MisalignedSample(usize, usize),
+    #[error("Unknown token type: {0}, expected one of uint16, uint32")]
+    InvalidTokenWidth(String),
+}
+
+/// Size of the little-endian tokens samples are made of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenWidth {
+    U16,
+    U32,
+}
+
+impl TokenWidth {
+    pub fn bytes(&self) -> usize {
+        match self {
+            TokenWidth::U16 => 2,
+            TokenWidth::U32 => 4,
+        }
+    }
+
+    fn encode(&self, token: u32) -> Result<Vec<u8>> {
+        match self {
+            TokenWidth::U16 => {
+                let token = u16::try_from(token).map_err(|_| PackError::InvalidSeparator(token, 2))?;
+                Ok(token.to_le_bytes().to_vec())
+            }
+            TokenWidth::U32 => Ok(token.to_le_bytes().to_vec()),
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> u32 {
+        match self {
+            TokenWidth::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+            TokenWidth::U32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    }
+}
+
+impl FromStr for TokenWidth {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "uint16" => Ok(Self::U16),
+            "uint32" => Ok(Self::U32),
+            _ => bail!(PackError::InvalidTokenWidth(s.to_string())),
+        }
+    }
+}
+
+/// How documents are concatenated into training sequences.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packing {
+    width: TokenWidth,
+    seq_len: usize,
+    separator: Option<u32>,
+    segments: bool,
+    drop_last: bool,
+}
+
+impl Packing {
+    /// Sequences of `seq_len` tokens, the last shorter sequence is dropped unless [`Packing::keep_last`] is set.
+    pub fn new(width: TokenWidth, seq_len: usize) -> Self {
+        Self {
+            width,
+            seq_len,
+            separator: None,
+            segments: false,
+            drop_last: true,
+        }
+    }
+
+    /// Token appended after every document.
+    pub fn with_separator(&mut self, token: u32) -> &mut Self {
+        self.separator = Some(token);
+        self
+    }
+
+    /// Also yields the index within its sequence of the document every token belongs to.
+    pub fn with_segments(&mut self) -> &mut Self {
+        self.segments = true;
+        self
+    }
+
+    pub fn keep_last(&mut self) -> &mut Self {
+        self.drop_last = false;
+        self
+    }
+
+    /// Checks the packing against the byte length of every sample it will be given.
+    pub(crate) fn check(&self, lengths: &[usize]) -> Result<()> {
+        ensure!(self.seq_len > 0, PackError::InvalidSequenceLength);
+        if let Some(separator) = self.separator {
+            self.width.encode(separator)?;
+        }
+        let width = self.width.bytes();
+        if let Some(length) = lengths.iter().find(|length| *length % width != 0) {
+            bail!(PackError::MisalignedSample(*length, width));
+        }
+        Ok(())
+    }
+}
+
+/// Fixed length run of tokens, possibly spanning several documents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sequence {
+    width: TokenWidth,
+    data: Bytes,
+    segments: Option<Vec<u32>>,
+}
+
+impl Sequence {
+    /// The tokens as little-endian bytes, in the width of the samples.
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    pub fn tokens(&self) -> Vec<u32> {
+        self.data
+            .chunks_exact(self.width.bytes())
+            .map(|token| self.width.decode(token))
+            .collect()
+    }
+
+    pub fn segments(&self) -> Option<&[u32]> {
+        self.segments.as_deref()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() / self.width.bytes()
+    }
+}
+
+/// Position of a [`PackIter`], the reader position and how far into its current document it is.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PackState {
+    pub(crate) reader: ReaderState,
+    pub(crate) carry: Option<(YieldedId, usize)>,
+}
+
+impl PackState {
+    fn get_options() -> impl Options {
+        bincode::DefaultOptions::new().with_varint_encoding().with_big_endian()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Self::get_options()
+            .serialize(self)
+            .map_err(|e| eyre!(e))
+            .wrap_err("Failed to write pack state")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::get_options()
+            .deserialize(bytes)
+            .map_err(|e| eyre!(e))
+            .wrap_err("Failed to read pack state")
+    }
+}
+
+/// Document being packed and the number of its tokens, separator included, already used.
+struct Document {
+    id: YieldedId,
+    value: Bytes,
+    offset: usize,
+}
+
+/// Concatenates documents into sequences, the tokens left over from one sequence start the next.
+///
+/// Tokens packed before an error are kept for the sequence following it.
+pub struct PackIter {
+    samples: SampleIter,
+    packing: Packing,
+    separator: Bytes,
+    current: Option<Document>,
+    data: BytesMut,
+    segments: Vec<u32>,
+    segment: u32,
+}
+
+impl PackIter {
+    /// `carry` is the document a [`PackState`] was in the middle of, read back with its offset.
+    pub(crate) fn new(
+        samples: SampleIter,
+        packing: &Packing,
+        carry: Option<(YieldedId, Bytes, usize)>,
+    ) -> Result<Self> {
+        let separator = match packing.separator {
+            Some(token) => Bytes::from(packing.width.encode(token)?),
+            None => Bytes::new(),
+        };
+        Ok(Self {
+            samples,
+            packing: packing.clone(),
+            separator,
+            current: carry.map(|(id, value, offset)| Document { id, value, offset }),
+            data: BytesMut::new(),
+            segments: Vec::new(),
+            segment: 0,
+        })
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.samples.epoch()
+    }
+
+    pub fn state(&self) -> PackState {
+        PackState {
+            reader: self.samples.state(),
+            carry: self.current.as_ref().map(|doc| (doc.id, doc.offset)),
+        }
+    }
+
+    /// Makes sure there is a current document, `None` once the reader is exhausted.
+    fn next_document(&mut self) -> Option<Result<()>> {
+        if self.current.is_none() {
+            let (id, (_, value)) = match self.samples.next_with_id()? {
+                Ok(item) => item,
+                Err(err) => return Some(Err(err)),
+            };
+            self.current = Some(Document { id, value, offset: 0 });
+        }
+        Some(Ok(()))
+    }
+}
+
+impl Iterator for PackIter {
+    type Item = Result<Sequence>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let width = self.packing.width.bytes();
+        let full = self.packing.seq_len * width;
+        self.data.reserve(full - self.data.len());
+        while self.data.len() < full {
+            match self.next_document() {
+                Some(Ok(())) => {}
+                Some(Err(err)) => return Some(Err(err)),
+                None => break,
+            }
+            let doc = self.current.as_mut().unwrap();
+            let len = doc.value.len() + self.separator.len();
+            let start = doc.offset * width;
+            let end = len.min(start + full - self.data.len());
+            let split = doc.value.len();
+            self.data
+                .extend_from_slice(&doc.value[start.min(split)..end.min(split)]);
+            self.data
+                .extend_from_slice(&self.separator[start.max(split) - split..end.max(split) - split]);
+            self.segments
+                .extend(std::iter::repeat_n(self.segment, (end - start) / width));
+            doc.offset = end / width;
+            if end == len {
+                self.current = None;
+                self.segment += (end > start) as u32;
+            }
+        }
+
+        let data = mem::take(&mut self.data);
+        let segments = mem::take(&mut self.segments);
+        self.segment = 0;
+        if data.is_empty() || (self.packing.drop_last && data.len() < full) {
+            return None;
+        }
+        Some(Ok(Sequence {
+            width: self.packing.width,
+            data: data.freeze(),
+            segments: self.packing.segments.then_some(segments),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Writer;
+    use crate::reader::Reader;
+    use crate::test_setup::*;
+
+    /// Archive of 20 documents, document `i` holds `i + 1` tokens all equal to `i + 1`.
+    fn token_file(width: TokenWidth) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = Writer::new(file.as_file().try_clone().unwrap(), 1024, 10 * 1024).unwrap();
+        for i in 0..20u32 {
+            let tokens: Vec<u8> = (0..=i).flat_map(|_| width.encode(i + 1).unwrap()).collect();
+            writer.write(&format!("{i:06}"), &tokens).unwrap();
+        }
+        writer.close().unwrap();
+        file
+    }
+
+    fn reader(file: &tempfile::NamedTempFile) -> Reader {
+        let mut reader = Reader::new();
+        reader.open_file(file.path().to_str().unwrap()).unwrap().by_count(3);
+        reader
+    }
+
+    #[test]
+    fn test_packing() {
+        setup();
+        let file = token_file(TokenWidth::U16);
+        let reader = reader(&file);
+        let mut packing = Packing::new(TokenWidth::U16, 8);
+        packing.with_separator(0).with_segments();
+        let sequences: Vec<Sequence> = reader.iter_packed(&packing).unwrap().map(Result::unwrap).collect();
+
+        // 210 tokens plus 20 separators.
+        assert_eq!(sequences.len(), 230 / 8);
+        assert_eq!(sequences[0].tokens(), vec![1, 0, 2, 2, 0, 3, 3, 3]);
+        assert_eq!(sequences[0].segments().unwrap(), &[0, 0, 1, 1, 1, 2, 2, 2]);
+        assert_eq!(sequences[1].tokens(), vec![0, 4, 4, 4, 4, 0, 5, 5]);
+        assert_eq!(sequences[1].segments().unwrap(), &[0, 1, 1, 1, 1, 1, 2, 2]);
+        let all: Vec<u32> = sequences.iter().flat_map(|sequence| sequence.tokens()).collect();
+        assert_eq!(all.iter().filter(|token| **token == 20).count(), 15);
+
+        packing.keep_last();
+        let last = reader.iter_packed(&packing).unwrap().last().unwrap().unwrap();
+        assert_eq!(last.len(), 230 % 8);
+        assert_eq!(last.data().len(), 230 % 8 * 2);
+    }
+
+    #[test]
+    fn test_packing_checks_samples() {
+        setup();
+        let file = new_dummy_path(4, 3);
+        let reader = reader(file.as_ref().unwrap());
+        let err = reader.iter_packed(&Packing::new(TokenWidth::U16, 8)).err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(PackError::MisalignedSample(3, 2))));
+        let err = reader
+            .iter_packed(Packing::new(TokenWidth::U16, 8).with_separator(1 << 16))
+            .err()
+            .unwrap();
+        assert!(matches!(err.downcast_ref(), Some(PackError::InvalidSeparator(..))));
+        assert_eq!("uint32".parse::<TokenWidth>().unwrap(), TokenWidth::U32);
+    }
+
+    #[test]
+    fn test_packing_resume() {
+        setup();
+        let file = token_file(TokenWidth::U32);
+        let mut reader = reader(&file);
+        reader.with_shuffling(Some(3)).with_sharding(1, 2).unwrap();
+        let mut packing = Packing::new(TokenWidth::U32, 7);
+        packing.with_separator(99).keep_last();
+        let expected: Vec<Sequence> = reader.iter_packed(&packing).unwrap().map(Result::unwrap).collect();
+
+        for taken in [0, 1, 5, 9] {
+            let mut iter = reader.iter_packed(&packing).unwrap();
+            let mut sequences: Vec<Sequence> = iter.by_ref().take(taken).map(Result::unwrap).collect();
+            let state = PackState::from_bytes(&iter.state().to_bytes().unwrap()).unwrap();
+            sequences.extend(reader.iter_packed_from(&state, &packing).unwrap().map(Result::unwrap));
+            assert_eq!(sequences, expected);
+        }
+    }
+}
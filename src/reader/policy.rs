@@ -0,0 +1,133 @@
+use std::ops::Range;
+
+use color_eyre::eyre::{bail, Result};
+use thiserror::Error;
+
+use crate::reader::block::{Block, SampleId};
+use crate::reader::datasource::DataSource;
+use crate::reader::Sample;
+
+#[derive(Error, Debug)]
+pub enum ReadError {
+    #[error("Failed to read samples {first}..={last} at bytes {bytes:?} of archive {archive}")]
+    Block {
+        archive: usize,
+        first: String,
+        last: String,
+        bytes: Range<usize>,
+    },
+    #[error("Unknown error policy: {0}, expected one of raise, skip_sample, skip_block, retry")]
+    InvalidPolicy(String),
+}
+
+/// What iterating does when a block cannot be read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// The error is yielded, iterating further goes on with the next block.
+    #[default]
+    Raise,
+    /// The samples of the block are read one at a time and the ones that fail are left out.
+    SkipSample,
+    /// The whole block is left out.
+    SkipBlock,
+    /// The block is read again up to this many times before the error is yielded.
+    Retry(u32),
+}
+
+impl ErrorPolicy {
+    /// Policy called `name`, `retries` is only used by `retry`.
+    pub fn from_name(name: &str, retries: u32) -> Result<Self> {
+        match name {
+            "raise" => Ok(Self::Raise),
+            "skip_sample" => Ok(Self::SkipSample),
+            "skip_block" => Ok(Self::SkipBlock),
+            "retry" => Ok(Self::Retry(retries)),
+            _ => bail!(ReadError::InvalidPolicy(name.to_string())),
+        }
+    }
+
+    /// Reads the samples of `block`, an error that is not handled names the block it happened in.
+    pub(crate) async fn read_block(self, block: Block, datasource: DataSource) -> Result<Vec<(SampleId, Sample)>> {
+        let attempts = match self {
+            ErrorPolicy::Retry(retries) => retries + 1,
+            _ => 1,
+        };
+        let mut result = read_samples(&block, &datasource).await;
+        for _ in 1..attempts {
+            if result.is_ok() {
+                break;
+            }
+            result = read_samples(&block, &datasource).await;
+        }
+
+        let err = match result {
+            Ok(samples) => return Ok(samples),
+            Err(err) => err,
+        };
+        match self {
+            ErrorPolicy::SkipBlock => Ok(Vec::new()),
+            ErrorPolicy::SkipSample => {
+                let mut samples = Vec::new();
+                for single in block.split() {
+                    if let Ok(read) = read_samples(&single, &datasource).await {
+                        samples.extend(read);
+                    }
+                }
+                Ok(samples)
+            }
+            ErrorPolicy::Raise | ErrorPolicy::Retry(_) => Err(err.wrap_err(block.read_error())),
+        }
+    }
+}
+
+async fn read_samples(block: &Block, datasource: &DataSource) -> Result<Vec<(SampleId, Sample)>> {
+    let read = block.clone().read_source(datasource.clone()).await?;
+    Ok(block.ids().zip(read.to_vec()?).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use super::*;
+    use crate::reader::Reader;
+    use crate::test_setup::*;
+
+    fn keys(reader: &mut Reader, policy: ErrorPolicy) -> Vec<Result<String>> {
+        let iter = reader.with_error_policy(policy).iter().unwrap();
+        iter.map(|sample| sample.map(|(key, _)| key)).collect()
+    }
+
+    #[test]
+    fn test_error_policies() {
+        setup();
+        let file = new_dummy_path(32, 10).unwrap();
+        let mut reader = Reader::new();
+        reader.open_file(file.path().to_str().unwrap()).unwrap().by_count(4);
+        // Cuts off the last sample and half of the one before it.
+        let archive = OpenOptions::new().write(true).open(file.path()).unwrap();
+        archive.set_len(archive.metadata().unwrap().len() - 15).unwrap();
+
+        let raised = keys(&mut reader, ErrorPolicy::Raise);
+        assert_eq!(raised.len(), 29);
+        assert!(raised[..28].iter().all(Result::is_ok));
+        let err = raised[28].as_ref().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ReadError::Block { archive: 0, first, last, .. }) if first == "000028" && last == "000031"
+        ));
+        assert_eq!(keys(&mut reader, ErrorPolicy::Retry(2)).len(), 29);
+
+        let skipped: Vec<String> = keys(&mut reader, ErrorPolicy::SkipBlock)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(skipped, (0..28).map(|i| format!("{i:06}")).collect::<Vec<_>>());
+        let kept: Vec<String> = keys(&mut reader, ErrorPolicy::SkipSample)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(kept, (0..30).map(|i| format!("{i:06}")).collect::<Vec<_>>());
+        assert!(ErrorPolicy::from_name("ignore", 0).is_err());
+    }
+}
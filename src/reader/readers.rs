@@ -1,23 +1,137 @@
-use std::fs::File;
-use std::rc::Rc;
+use std::fs::{self, File};
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
 use color_eyre::eyre::{ensure, eyre, Result, WrapErr};
 #[cfg(feature = "gcs")]
 use gcs_reader::{Auth, GCSReader};
+use glob::glob;
+use regex::Regex;
+use thiserror::Error;
 
-use crate::header::Header;
-use crate::reader::collector::Collector;
-use crate::reader::datasource::DataSource;
+use crate::header::{Header, SampleMD};
+use crate::reader::block::{Block, Window};
+use crate::reader::bucket::{BucketIter, BucketState, Bucketing};
+use crate::reader::cache::{BlockCache, CachedSource};
+use crate::reader::coalesce::Coalescing;
+use crate::reader::collector::{Collector, Repeat};
+use crate::reader::datasource::{DataSource, FilePool, MappedFile, PooledFile, ReadPool, ThreadedFile};
+#[cfg(any(feature = "http", feature = "gcs"))]
+use crate::reader::disk_cache::{DiskCache, DiskCachedSource};
+use crate::reader::filter::Filter;
+#[cfg(feature = "http")]
+use crate::reader::http::{HeaderFetch, HttpClient, HttpSource};
+use crate::reader::iter::{ReaderState, SampleIter};
+use crate::reader::pack::{PackIter, PackState, Packing};
+use crate::reader::policy::ErrorPolicy;
+use crate::reader::retry::{RetryPolicy, RetryingSource};
+#[cfg(feature = "s3")]
+use crate::reader::s3::S3Config;
+use crate::reader::sampling::Sampling;
+use crate::reader::shard::ShardMode;
+use crate::reader::DatasetError;
 
 pub type Sample = (String, Bytes);
-pub type RcHeader = Rc<Header>;
+pub type ArcHeader = Arc<Header>;
 
-#[derive(Default)]
+#[derive(Error, Debug)]
+pub enum ReaderError {
+    #[error("Reader has not been opened")]
+    Unopened,
+    #[error("No archives found in {0}")]
+    NoArchives(String),
+    #[error("Window length and alignment must be greater than 0, got len: {0}, align: {1}")]
+    InvalidWindow(usize, usize),
+    #[error("Shuffle buffer size must be greater than 0")]
+    InvalidShuffleBuffer,
+    #[error("Disk cache chunk size must be greater than 0")]
+    InvalidChunkSize,
+    #[error("Range {0:?} out of bounds for sample of length {1}")]
+    RangeOutOfBounds(Range<usize>, usize),
+    #[error("Key {0} is in {1} archives")]
+    AmbiguousKey(String, usize),
+}
+
+/// One opened archive, blocks refer to it by its position in the reader.
+#[derive(Clone)]
+pub(crate) struct Archive {
+    pub(crate) header: ArcHeader,
+    /// The origin with the retries and caches of the reader layered on top.
+    pub(crate) datasource: DataSource,
+    origin: Origin,
+}
+
+/// What an archive is read from, before anything is layered on top.
+#[derive(Clone)]
+enum Origin {
+    Local(DataSource),
+    /// A remote archive, cached on disk by its URI and version.
+    #[cfg(any(feature = "http", feature = "gcs"))]
+    Remote(String, Option<String>, Remote),
+}
+
+/// How a remote archive is read, requests over HTTP are given the timeout of the retry policy.
+#[cfg(any(feature = "http", feature = "gcs"))]
+#[derive(Clone)]
+enum Remote {
+    #[cfg(feature = "http")]
+    Http(HttpSource),
+    #[cfg(feature = "gcs")]
+    Gcs(DataSource),
+}
+
+impl Archive {
+    fn local(header: Header, datasource: DataSource) -> Self {
+        Self {
+            header: Arc::new(header),
+            datasource: datasource.clone(),
+            origin: Origin::Local(datasource),
+        }
+    }
+
+    /// Whether the archive is read asynchronously whatever is layered on top, which buffering relies on.
+    fn is_async(&self) -> bool {
+        match &self.origin {
+            Origin::Local(datasource) => datasource.is_async(),
+            #[cfg(any(feature = "http", feature = "gcs"))]
+            Origin::Remote(..) => true,
+        }
+    }
+
+    /// `version` tells the contents at `uri` apart from what was there before, e.g. an ETag.
+    /// Its datasource is only set once layered.
+    #[cfg(any(feature = "http", feature = "gcs"))]
+    fn remote(uri: &str, version: Option<String>, header: Header, remote: Remote) -> Self {
+        let datasource = match &remote {
+            #[cfg(feature = "http")]
+            Remote::Http(source) => DataSource::new_async(source.clone()),
+            #[cfg(feature = "gcs")]
+            Remote::Gcs(datasource) => datasource.clone(),
+        };
+        Self {
+            header: Arc::new(header),
+            datasource,
+            origin: Origin::Remote(uri.to_string(), version, remote),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct Reader {
     collector: Collector,
-    header: Option<RcHeader>,
-    datasource: Option<DataSource>,
+    archives: Vec<Archive>,
+    pool: Arc<Mutex<FilePool>>,
+    pub(crate) read_pool: Arc<ReadPool>,
+    #[cfg(feature = "http")]
+    http_pool: Arc<ReadPool>,
+    #[cfg(any(feature = "http", feature = "gcs"))]
+    disk_cache: Option<Arc<DiskCache>>,
+    retries: Option<RetryPolicy>,
+    block_cache: Option<Arc<BlockCache>>,
+    #[cfg(feature = "http")]
+    http: HttpClient,
 }
 
 impl Reader {
@@ -25,12 +139,74 @@ impl Reader {
         Self::default()
     }
 
+    /// Opens an archive read on a pool of threads sized by [`Reader::with_buffering`], directly if not buffering.
     pub fn open_file(&mut self, path: &str) -> Result<&mut Self> {
         let mut data = File::open(path).wrap_err_with(|| format!("Failed to open file from {}", path))?;
         let header = Header::read(&mut data)?;
-        self.header = Some(Rc::new(header));
-        self.datasource = Some(DataSource::new_sync(data));
-        Ok(self)
+        let datasource = DataSource::new_async(ThreadedFile::new(data, self.read_pool.clone()));
+        Ok(self.set_archives(vec![Archive::local(header, datasource)]))
+    }
+
+    /// Opens an archive served over HTTP(S) by a server supporting range requests, such as a CDN or a signed URL.
+    #[cfg(feature = "http")]
+    pub fn open_http(&mut self, url: &str, header_fetch: HeaderFetch) -> Result<&mut Self> {
+        let data = self.http_source(url);
+        let (header, etag) = data.read_header(header_fetch)?;
+        Ok(self.set_archives(vec![Archive::remote(url, etag, header, Remote::Http(data))]))
+    }
+
+    /// Opens an archive read through a memory map, falling back to regular reads if the file cannot be mapped.
+    pub fn open_file_mmap(&mut self, path: &str) -> Result<&mut Self> {
+        let mut data = File::open(path).wrap_err_with(|| format!("Failed to open file from {}", path))?;
+        let header = Header::read(&mut data)?;
+        let datasource = match MappedFile::map(data) {
+            Ok(mapped) => DataSource::new_sync(mapped),
+            Err(data) => DataSource::new_sync(data),
+        };
+        Ok(self.set_archives(vec![Archive::local(header, datasource)]))
+    }
+
+    /// Opens several archives as one dataset, their files are only kept open while being read.
+    pub fn open_files<P: AsRef<Path>>(&mut self, paths: &[P]) -> Result<&mut Self> {
+        let archives = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let mut data =
+                    File::open(path).wrap_err_with(|| format!("Failed to open file from {}", path.display()))?;
+                let header = Header::read(&mut data)?;
+                let file = PooledFile::new(path.to_path_buf(), self.pool.clone(), self.read_pool.clone());
+                let datasource = DataSource::new_async(file);
+                Ok(Archive::local(header, datasource))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(self.set_archives(archives))
+    }
+
+    /// Opens every archive matching `pattern`, in lexicographic order.
+    pub fn open_glob(&mut self, pattern: &str) -> Result<&mut Self> {
+        let paths = glob(pattern)?.collect::<Result<Vec<PathBuf>, _>>()?;
+        ensure!(!paths.is_empty(), ReaderError::NoArchives(pattern.to_string()));
+        self.open_files(&paths)
+    }
+
+    /// Opens the archives listed one per line in a manifest, relative paths are resolved against its directory.
+    pub fn open_manifest(&mut self, path: &str) -> Result<&mut Self> {
+        let manifest = fs::read_to_string(path).wrap_err_with(|| format!("Failed to read manifest from {}", path))?;
+        let root = Path::new(path).parent().unwrap_or(Path::new(""));
+        let paths: Vec<PathBuf> = manifest
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| root.join(line))
+            .collect();
+        ensure!(!paths.is_empty(), ReaderError::NoArchives(path.to_string()));
+        self.open_files(&paths)
+    }
+
+    pub fn with_max_open_files(&mut self, max_open: usize) -> &mut Self {
+        self.pool.lock().unwrap().set_max_open(max_open);
+        self
     }
 
     pub fn by_size(&mut self, size: usize) -> &mut Self {
@@ -43,29 +219,393 @@ impl Reader {
         self
     }
 
+    pub fn shuffle_buffer_by_size(&mut self, size: usize) -> Result<&mut Self> {
+        ensure!(size > 0, ReaderError::InvalidShuffleBuffer);
+        self.collector.shuffle_buffer_by_size(size);
+        Ok(self)
+    }
+
+    pub fn shuffle_buffer_by_count(&mut self, count: usize) -> Result<&mut Self> {
+        ensure!(count > 0, ReaderError::InvalidShuffleBuffer);
+        self.collector.shuffle_buffer_by_count(count);
+        Ok(self)
+    }
+
     pub fn with_shuffling(&mut self, seed: Option<u64>) -> &mut Self {
         self.collector.with_shuffling(seed);
         self
     }
 
+    pub fn set_epoch(&mut self, epoch: u64) -> &mut Self {
+        self.collector.set_epoch(epoch);
+        self
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.collector.epoch()
+    }
+
+    /// Iterates `epochs` epochs in a row starting from the current one, each shuffled with its own seed.
+    pub fn repeat(&mut self, epochs: u64) -> &mut Self {
+        self.collector.with_repeat(Repeat::Times(epochs));
+        self
+    }
+
+    /// Iterates epoch after epoch without ever ending.
+    pub fn repeat_forever(&mut self) -> &mut Self {
+        self.collector.with_repeat(Repeat::Forever);
+        self
+    }
+
     pub fn with_sharding(&mut self, rank: u16, world_size: u16) -> Result<&mut Self> {
         self.collector.with_sharding(rank, world_size)?;
         Ok(self)
     }
 
+    pub fn with_nested_sharding(&mut self, rank: u16, world_size: u16) -> Result<&mut Self> {
+        self.collector.with_nested_sharding(rank, world_size)?;
+        Ok(self)
+    }
+
+    pub fn with_worker_sharding(&mut self, worker: u16, num_workers: u16) -> Result<&mut Self> {
+        self.collector.with_worker_sharding(worker, num_workers)?;
+        Ok(self)
+    }
+
+    pub fn with_shard_mode(&mut self, mode: ShardMode) -> &mut Self {
+        self.collector.with_shard_mode(mode);
+        self
+    }
+
+    pub fn balance_shards_by_size(&mut self) -> &mut Self {
+        self.collector.balance_shards_by_size();
+        self
+    }
+
+    /// Only reads samples whose key starts with `prefix`.
+    pub fn filter_prefix(&mut self, prefix: &str) -> &mut Self {
+        self.collector.with_filter(Filter::Prefix(prefix.to_string()));
+        self
+    }
+
+    /// Only reads samples whose key matches `pattern` anywhere.
+    pub fn filter_regex(&mut self, pattern: &str) -> Result<&mut Self> {
+        self.collector.with_filter(Filter::Regex(Regex::new(pattern)?));
+        Ok(self)
+    }
+
+    /// Only reads the samples listed in `keys`.
+    pub fn filter_keys<I: IntoIterator<Item = String>>(&mut self, keys: I) -> &mut Self {
+        self.collector
+            .with_filter(Filter::Allow(Arc::new(keys.into_iter().collect())));
+        self
+    }
+
+    /// Skips the samples listed in `keys`.
+    pub fn exclude_keys<I: IntoIterator<Item = String>>(&mut self, keys: I) -> &mut Self {
+        self.collector
+            .with_filter(Filter::Deny(Arc::new(keys.into_iter().collect())));
+        self
+    }
+
+    /// Only reads samples of at least `size.start()` and at most `size.end()` bytes.
+    pub fn filter_size(&mut self, size: RangeInclusive<usize>) -> &mut Self {
+        self.collector.with_filter(Filter::Size(size));
+        self
+    }
+
+    /// Only reads samples for which `predicate` holds, it is given the key and metadata of every sample.
+    pub fn filter<F: Fn(&str, &SampleMD) -> bool + Send + Sync + 'static>(&mut self, predicate: F) -> &mut Self {
+        self.collector.with_filter(Filter::Predicate(Arc::new(predicate)));
+        self
+    }
+
+    /// Draws samples by weight, `weights` holds one per sample of every archive in the order they were opened.
+    ///
+    /// Samples with a weight of 0 are never read, without replacement each other sample is read once per epoch.
+    pub fn with_sample_weights(&mut self, weights: &[f64]) -> Result<&mut Self> {
+        let lens: Vec<usize> = self.archives()?.iter().map(|archive| archive.header.len()).collect();
+        let weights = Sampling::split_weights(weights, &lens)?;
+        self.collector.sampling().with_weights(weights);
+        Ok(self)
+    }
+
+    /// Draws samples by the weights stored under `key` in every archive, as little-endian f32s.
+    ///
+    /// The weights sample itself is never drawn.
+    pub fn with_stored_weights(&mut self, key: &str) -> Result<&mut Self> {
+        let weights = self
+            .archives()?
+            .iter()
+            .enumerate()
+            .map(|(archive, Archive { header, datasource, .. })| {
+                let idx = header
+                    .entries()
+                    .get_index_of(key)
+                    .ok_or(DatasetError::KeyNotFound(key.to_string()))?;
+                let block = Block::from_range(archive, header.clone(), idx..idx + 1).read_from(datasource)?;
+                let (_, value) = block.to_vec()?.pop().ok_or(eyre!("Invalid range"))?;
+                let mut weights = Sampling::decode_weights(&value, header.len())?;
+                weights[idx] = 0.0;
+                Sampling::split_weights(&weights, &[header.len()])
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.collector
+            .sampling()
+            .with_weights(weights.into_iter().flatten().collect());
+        Ok(self)
+    }
+
+    /// Draws with replacement, `num_samples` per epoch or as many as there are samples.
+    pub fn with_replacement(&mut self, num_samples: Option<usize>) -> &mut Self {
+        self.collector.sampling().with_replacement(num_samples);
+        self
+    }
+
+    /// Draws whole blocks weighted by the sum of their samples' weights, which keeps reads sequential.
+    pub fn sample_by_block(&mut self) -> &mut Self {
+        self.collector.sampling().by_block();
+        self
+    }
+
+    /// How iterating handles blocks that cannot be read, by default the error is yielded.
+    pub fn with_error_policy(&mut self, policy: ErrorPolicy) -> &mut Self {
+        self.collector.with_error_policy(policy);
+        self
+    }
+
+    /// Only reads a random crop of `len` bytes out of every longer sample, starting at a multiple of `align`.
+    ///
+    /// The crops change every epoch and are the same again when resuming.
+    pub fn with_random_window(&mut self, len: usize, align: usize) -> Result<&mut Self> {
+        ensure!(len > 0 && align > 0, ReaderError::InvalidWindow(len, align));
+        self.collector.with_window(Window { len, align });
+        Ok(self)
+    }
+
+    /// Retries failed or slow reads of the archives opened before or after, replacing any earlier policy.
+    ///
+    /// Every archive but memory mapped files is retried, those are read as they are. With a timeout or hedging, files
+    /// are read on at least one thread of their own even without buffering, as a read on the iterating thread could
+    /// not be given up on.
+    pub fn with_retries(&mut self, policy: &RetryPolicy) -> Result<&mut Self> {
+        policy.check()?;
+        if policy.interrupts_reads() {
+            self.read_pool.grow(1);
+        }
+        self.retries = Some(policy.clone());
+        self.layer_archives();
+        Ok(self)
+    }
+
+    /// Reads blocks close to each other in an archive with one request of at most `max_bytes`, also reading the gaps of
+    /// up to `max_gap` bytes between them. Blocks are still yielded in the same order.
+    pub fn with_coalescing(&mut self, max_bytes: usize, max_gap: usize) -> &mut Self {
+        self.collector.with_coalescing(Coalescing { max_bytes, max_gap });
+        self
+    }
+
+    /// Keeps up to `capacity` bytes of reads of the archives opened before or after in memory, shared by clones of the
+    /// reader. The cache starts empty again whenever other archives are opened.
+    pub fn with_block_cache(&mut self, capacity: usize) -> &mut Self {
+        self.block_cache = Some(Arc::new(BlockCache::new(capacity)));
+        self.layer_archives();
+        self
+    }
+
+    /// Caches the remote archives opened before or after in `dir`, using at most `max_bytes` of disk.
+    ///
+    /// Archives are fetched in aligned chunks of `chunk_size` bytes, so a read of fewer bytes still fetches its whole
+    /// chunk the first time, later reads of a cached chunk only read their bytes. Chunks about the size of the blocks
+    /// read keep the bytes fetched for nothing low. The directory can be shared by several processes, e.g. by the
+    /// workers of every `DataLoader` on a node.
+    #[cfg(any(feature = "http", feature = "gcs"))]
+    pub fn with_disk_cache(&mut self, dir: &str, max_bytes: u64, chunk_size: usize) -> Result<&mut Self> {
+        ensure!(chunk_size > 0, ReaderError::InvalidChunkSize);
+        self.disk_cache = Some(Arc::new(DiskCache::new(dir, max_bytes, chunk_size)?));
+        self.layer_archives();
+        Ok(self)
+    }
+
+    fn set_archives(&mut self, archives: Vec<Archive>) -> &mut Self {
+        self.archives = archives;
+        // Cached reads are keyed by the position of their archive, which now is another one.
+        self.block_cache = self.block_cache.as_ref().map(|cache| Arc::new(cache.emptied()));
+        self.layer_archives();
+        self
+    }
+
+    /// Requests over HTTP run on their own threads, at least two so a read can be hedged even without buffering.
+    #[cfg(feature = "http")]
+    fn http_source(&self, url: &str) -> HttpSource {
+        self.http_pool.grow(2);
+        let timeout = self.retries.as_ref().and_then(RetryPolicy::timeout);
+        HttpSource::new(&self.http, url, self.http_pool.clone()).with_timeout(timeout)
+    }
+
+    /// Layers the disk cache, retries and block cache of the reader over the origin of every archive.
+    fn layer_archives(&mut self) {
+        let layered: Vec<DataSource> = self
+            .archives
+            .iter()
+            .enumerate()
+            .map(|(id, archive)| self.layer(id, archive))
+            .collect();
+        for (archive, datasource) in self.archives.iter_mut().zip(layered) {
+            archive.datasource = datasource;
+        }
+    }
+
+    fn layer(&self, id: usize, archive: &Archive) -> DataSource {
+        let mut datasource = match &archive.origin {
+            Origin::Local(datasource) => datasource.clone(),
+            #[cfg(any(feature = "http", feature = "gcs"))]
+            Origin::Remote(uri, version, remote) => {
+                let datasource = match remote {
+                    #[cfg(feature = "http")]
+                    Remote::Http(source) => {
+                        let timeout = self.retries.as_ref().and_then(RetryPolicy::timeout);
+                        DataSource::new_async(source.clone().with_timeout(timeout))
+                    }
+                    #[cfg(feature = "gcs")]
+                    Remote::Gcs(datasource) => datasource.clone(),
+                };
+                match &self.disk_cache {
+                    Some(cache) => DataSource::new_async(DiskCachedSource::new(
+                        datasource,
+                        cache.clone(),
+                        uri,
+                        &archive.header,
+                        version.as_deref(),
+                    )),
+                    None => datasource,
+                }
+            }
+        };
+        if let (Some(policy), DataSource::Async(inner)) = (&self.retries, &datasource) {
+            datasource = DataSource::new_async(RetryingSource::new(inner.clone(), policy.clone()));
+        }
+        if let Some(cache) = &self.block_cache {
+            datasource = DataSource::new_async(CachedSource::new(datasource, cache.clone(), id));
+        }
+        datasource
+    }
+
+    /// Keeps up to `buffer_size` reads in flight, local files are then read on as many threads. Requests over HTTP get
+    /// twice as many, leaving room for a hedge of every read.
     pub fn with_buffering(&mut self, buffer_size: u32) -> Result<&mut Self> {
+        ensure!(!self.archives.is_empty(), ReaderError::Unopened);
         ensure!(
-            self.datasource.as_ref().unwrap().is_async(),
-            eyre!("Buffering is only supported for async datasources")
+            self.archives.iter().all(Archive::is_async),
+            eyre!("Buffering is only supported for async datasources, every one but memory mapped files")
         );
+        self.read_pool.grow(buffer_size as usize);
+        #[cfg(feature = "http")]
+        self.http_pool.grow(2 * buffer_size as usize);
         self.collector.with_buffering(buffer_size);
         Ok(self)
     }
 
-    pub fn iter(&self) -> Result<impl Iterator<Item = Sample>> {
-        let header = self.header.clone().ok_or(eyre!("Unopened"))?;
-        let datasource = self.datasource.clone().unwrap();
-        Ok(self.collector.iter(header, datasource))
+    /// Number of samples one epoch yields on this rank, known before anything is read.
+    pub fn len(&self) -> Result<usize> {
+        self.collector.len(self.archives()?)
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Reads bytes `range` of the sample at `key`, without reading the rest of it. The key must be in exactly one
+    /// of the archives.
+    pub fn read_range(&self, key: &str, range: Range<usize>) -> Result<Bytes> {
+        let found: Vec<_> = self
+            .archives()?
+            .iter()
+            .filter_map(|archive| Some((archive, archive.header.get_key(key)?)))
+            .collect();
+        ensure!(
+            found.len() <= 1,
+            ReaderError::AmbiguousKey(key.to_string(), found.len())
+        );
+        let (archive, entry) = found
+            .into_iter()
+            .next()
+            .ok_or(DatasetError::KeyNotFound(key.to_string()))?;
+        ensure!(
+            range.start <= range.end && range.end <= entry.length(),
+            ReaderError::RangeOutOfBounds(range, entry.length())
+        );
+        let start = archive.header.byte_size() + entry.start_idx();
+        archive.datasource.get_range(start + range.start..start + range.end)
+    }
+
+    fn archives(&self) -> Result<&[Archive]> {
+        ensure!(!self.archives.is_empty(), ReaderError::Unopened);
+        Ok(&self.archives)
+    }
+
+    pub fn iter(&self) -> Result<SampleIter> {
+        self.collector.iter(self.archives()?, None)
+    }
+
+    /// Resumes iteration right after the last sample yielded by the iterator `state` was taken from.
+    pub fn iter_from(&self, state: &ReaderState) -> Result<SampleIter> {
+        self.collector.iter(self.archives()?, Some(state))
+    }
+
+    /// Batches samples of similar byte length, the buckets are planned from the headers before anything is read.
+    pub fn iter_buckets(&self, bucketing: &Bucketing, batch_size: usize, drop_last: bool) -> Result<BucketIter> {
+        let boundaries = bucketing.boundaries(&self.collector.sample_lengths(self.archives()?)?)?;
+        BucketIter::new(self.iter()?, boundaries, Vec::new(), batch_size, drop_last)
+    }
+
+    pub fn iter_buckets_from(
+        &self,
+        state: &BucketState,
+        bucketing: &Bucketing,
+        batch_size: usize,
+        drop_last: bool,
+    ) -> Result<BucketIter> {
+        let archives = self.archives()?;
+        let boundaries = bucketing.boundaries(&self.collector.sample_lengths(archives)?)?;
+        let buckets = state
+            .buckets
+            .iter()
+            .map(|ids| {
+                let samples = self.collector.read_ids(archives, ids)?;
+                Ok(ids.iter().copied().zip(samples).collect())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        BucketIter::new(
+            self.iter_from(&state.reader)?,
+            boundaries,
+            buckets,
+            batch_size,
+            drop_last,
+        )
+    }
+
+    /// Concatenates token samples into fixed length sequences, every sample must be a whole number of tokens.
+    pub fn iter_packed(&self, packing: &Packing) -> Result<PackIter> {
+        packing.check(&self.collector.sample_lengths(self.archives()?)?)?;
+        PackIter::new(self.iter()?, packing, None)
+    }
+
+    pub fn iter_packed_from(&self, state: &PackState, packing: &Packing) -> Result<PackIter> {
+        let archives = self.archives()?;
+        packing.check(&self.collector.sample_lengths(archives)?)?;
+        let carry = match state.carry {
+            Some((id, offset)) => {
+                let (_, value) = self
+                    .collector
+                    .read_ids(archives, &[id])?
+                    .pop()
+                    .ok_or(eyre!("Invalid range"))?;
+                Some((id, value, offset))
+            }
+            None => None,
+        };
+        PackIter::new(self.iter_from(&state.reader)?, packing, carry)
     }
 }
 
@@ -74,9 +614,21 @@ impl Reader {
     pub fn open_gcs(&mut self, uri: &str) -> Result<&mut Self> {
         let mut data = GCSReader::from_uri(uri, Auth::default())?;
         let header = Header::read(&mut data)?;
-        self.header = Some(Rc::new(header));
-        self.datasource = Some(DataSource::new_async(data));
-        Ok(self)
+        let remote = Remote::Gcs(DataSource::new_async(data));
+        Ok(self.set_archives(vec![Archive::remote(uri, None, header, remote)]))
+    }
+}
+
+#[cfg(feature = "s3")]
+impl Reader {
+    /// Opens an archive at `s3://bucket/key` of an S3-compatible object store, read like one served over HTTP.
+    pub fn open_s3(&mut self, uri: &str, config: &S3Config) -> Result<&mut Self> {
+        let mut data = self.http_source(&config.url(uri)?);
+        if let Some(signer) = config.signer() {
+            data = data.with_signer(Arc::new(signer));
+        }
+        let (header, etag) = data.read_header(HeaderFetch::default())?;
+        Ok(self.set_archives(vec![Archive::remote(uri, etag, header, Remote::Http(data))]))
     }
 }
 
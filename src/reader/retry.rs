@@ -0,0 +1,286 @@
+use std::ops::Range;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use color_eyre::eyre::{bail, ensure, Result};
+use futures::channel::oneshot;
+use futures::future::{select, select_ok, Either};
+use futures::FutureExt;
+use rand::Rng;
+use thiserror::Error;
+
+use crate::reader::datasource::AsyncDataSource;
+
+#[derive(Error, Debug)]
+pub enum RetryError {
+    #[error("Read of range {0:?} timed out after {1:?}")]
+    Timeout(Range<usize>, Duration),
+    #[error("Jitter must be between 0 and 1, got {0}")]
+    InvalidJitter(f64),
+    #[error("Range read failed after {0} retries")]
+    Exhausted(u32),
+}
+
+/// How a range read that fails or takes too long is tried again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    timeout: Option<Duration>,
+    hedge_after: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// Up to `max_retries` retries, waiting 100ms before the first and twice as long before every next one, up to 10s.
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.5,
+            timeout: None,
+            hedge_after: None,
+        }
+    }
+
+    pub fn with_backoff(&mut self, backoff: Duration, max_backoff: Duration) -> &mut Self {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Fraction of every backoff that is cut off at random, so readers failing together do not retry together.
+    pub fn with_jitter(&mut self, jitter: f64) -> &mut Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Gives up on a read after `timeout` and counts it as failed.
+    pub fn with_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Starts a duplicate read if the first has not finished after `after`, the first one to succeed is used.
+    pub fn with_hedging(&mut self, after: Duration) -> &mut Self {
+        self.hedge_after = Some(after);
+        self
+    }
+
+    #[cfg(feature = "http")]
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Whether reads are given up on or duplicated while they run, which they can only be off the awaiting thread.
+    pub(crate) fn interrupts_reads(&self) -> bool {
+        self.timeout.is_some() || self.hedge_after.is_some()
+    }
+
+    pub(crate) fn check(&self) -> Result<()> {
+        ensure!(
+            (0.0..=1.0).contains(&self.jitter),
+            RetryError::InvalidJitter(self.jitter)
+        );
+        Ok(())
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        backoff.mul_f64(1.0 - self.jitter * rand::thread_rng().gen::<f64>())
+    }
+}
+
+type Wake = (Instant, oneshot::Sender<()>);
+
+/// Resolves after `duration` whichever executor polls it, the deadlines are kept by one shared thread.
+pub(crate) async fn sleep(duration: Duration) {
+    static TIMER: OnceLock<Sender<Wake>> = OnceLock::new();
+    let timer = TIMER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel::<Wake>();
+        thread::spawn(move || {
+            let mut pending: Vec<Wake> = Vec::new();
+            loop {
+                let now = Instant::now();
+                let (due, waiting): (Vec<_>, Vec<_>) = pending.into_iter().partition(|(at, _)| *at <= now);
+                for (_, wake) in due {
+                    let _ = wake.send(());
+                }
+                pending = waiting.into_iter().filter(|(_, wake)| !wake.is_canceled()).collect();
+                let received = match pending.iter().map(|(at, _)| *at).min() {
+                    Some(next) => receiver.recv_timeout(next - now),
+                    None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(wake) => pending.push(wake),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        sender
+    });
+    let (sender, receiver) = oneshot::channel();
+    timer.send((Instant::now() + duration, sender)).unwrap();
+    let _ = receiver.await;
+}
+
+/// Retries the range reads of another source under a [`RetryPolicy`].
+pub(crate) struct RetryingSource {
+    inner: Arc<dyn AsyncDataSource>,
+    policy: RetryPolicy,
+}
+
+impl RetryingSource {
+    pub(crate) fn new(inner: Arc<dyn AsyncDataSource>, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    async fn read_once(&self, range: Range<usize>) -> Result<Bytes> {
+        let read = self.inner.get_range_async(range.clone());
+        let Some(timeout) = self.policy.timeout else {
+            return read.await;
+        };
+        match select(read, sleep(timeout).boxed()).await {
+            Either::Left((read, _)) => read,
+            Either::Right(_) => bail!(RetryError::Timeout(range, timeout)),
+        }
+    }
+
+    async fn attempt(&self, range: Range<usize>) -> Result<Bytes> {
+        let Some(after) = self.policy.hedge_after else {
+            return self.read_once(range).await;
+        };
+        let first = self.read_once(range.clone()).boxed();
+        let hedge = async move {
+            sleep(after).await;
+            self.read_once(range).await
+        };
+        let (read, _) = select_ok([first, hedge.boxed()]).await?;
+        Ok(read)
+    }
+}
+
+#[async_trait]
+impl AsyncDataSource for RetryingSource {
+    async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes> {
+        let mut retry = 0;
+        loop {
+            match self.attempt(range.clone()).await {
+                Ok(read) => return Ok(read),
+                Err(err) if retry == self.policy.max_retries => return Err(err.wrap_err(RetryError::Exhausted(retry))),
+                Err(_) => {
+                    sleep(self.policy.backoff(retry)).await;
+                    retry += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::reader::Reader;
+    use crate::test_setup::*;
+
+    /// Fails its first `failures` reads and stalls the `stalls` reads after them.
+    struct Faulty {
+        failures: u32,
+        stalls: u32,
+        reads: AtomicU32,
+    }
+
+    impl Faulty {
+        fn new(failures: u32, stalls: u32) -> Arc<Self> {
+            Arc::new(Self {
+                failures,
+                stalls,
+                reads: AtomicU32::new(0),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl AsyncDataSource for Faulty {
+        async fn get_range_async(&self, range: Range<usize>) -> Result<Bytes> {
+            let read = self.reads.fetch_add(1, Ordering::SeqCst);
+            if read < self.failures {
+                bail!("Injected failure");
+            }
+            if read < self.failures + self.stalls {
+                sleep(Duration::from_secs(60)).await;
+            }
+            Ok(Bytes::from(vec![1; range.len()]))
+        }
+    }
+
+    fn read(source: &Arc<Faulty>, policy: &RetryPolicy) -> Result<Bytes> {
+        let retrying = RetryingSource::new(source.clone(), policy.clone());
+        block_on(retrying.get_range_async(0..8))
+    }
+
+    #[test]
+    fn test_retries() {
+        setup();
+        let mut policy = RetryPolicy::new(3);
+        policy.with_backoff(Duration::from_millis(1), Duration::from_millis(4));
+        let flaky = Faulty::new(3, 0);
+        assert_eq!(read(&flaky, &policy).unwrap().len(), 8);
+        assert_eq!(flaky.reads.load(Ordering::SeqCst), 4);
+
+        let broken = Faulty::new(10, 0);
+        let err = read(&broken, &policy).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(RetryError::Exhausted(3))));
+        assert_eq!(broken.reads.load(Ordering::SeqCst), 4);
+
+        let stalled = Faulty::new(0, 1);
+        policy.with_timeout(Duration::from_millis(20));
+        assert!(read(&stalled, &policy).is_ok());
+        assert_eq!(stalled.reads.load(Ordering::SeqCst), 2);
+        assert!(RetryPolicy::new(0).with_jitter(1.5).check().is_err());
+    }
+
+    #[test]
+    fn test_hedging() {
+        setup();
+        let mut policy = RetryPolicy::new(0);
+        policy.with_hedging(Duration::from_millis(10));
+        let stalled = Faulty::new(0, 1);
+        let start = Instant::now();
+        assert!(read(&stalled, &policy).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(stalled.reads.load(Ordering::SeqCst), 2);
+
+        let file = new_dummy_path(16, 10).unwrap();
+        let mut reader = Reader::new();
+        reader.open_file(file.path().to_str().unwrap()).unwrap().by_count(4);
+        let expected: Vec<_> = reader.iter().unwrap().map(Result::unwrap).collect();
+        policy.with_timeout(Duration::from_secs(5));
+        reader.with_retries(&policy).unwrap().with_buffering(4).unwrap();
+        assert_eq!(reader.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), expected);
+
+        let mut reader = Reader::new();
+        reader.with_retries(&policy).unwrap().with_retries(&policy).unwrap();
+        reader.open_file(file.path().to_str().unwrap()).unwrap().by_count(4);
+        assert_eq!(reader.read_pool.threads(), 1);
+        assert_eq!(reader.iter().unwrap().map(Result::unwrap).collect::<Vec<_>>(), expected);
+
+        let mut reader = Reader::new();
+        reader.with_retries(&RetryPolicy::new(3)).unwrap();
+        assert_eq!(reader.read_pool.threads(), 0);
+    }
+}
@@ -0,0 +1,229 @@
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use color_eyre::eyre::{ensure, Result};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use thiserror::Error;
+
+use crate::reader::block::Block;
+
+#[derive(Error, Debug)]
+pub enum SamplingError {
+    #[error("Expected {1} weights, one per sample, got: {0}")]
+    LengthMismatch(usize, usize),
+    #[error("Weights must be non-negative and finite, got: {0}")]
+    InvalidWeight(f64),
+    #[error("Weights were given for archives of {0:?} samples, the reader has archives of {1:?}")]
+    ArchiveMismatch(Vec<usize>, Vec<usize>),
+}
+
+/// Draws the blocks of an epoch at random by weight instead of permuting all of them once.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Sampling {
+    weights: Option<Arc<Vec<Vec<f64>>>>,
+    replacement: bool,
+    num_samples: Option<usize>,
+    by_block: bool,
+}
+
+impl Sampling {
+    /// Checks `weights` against the sample count of every archive and splits it per archive.
+    pub(crate) fn split_weights(weights: &[f64], lens: &[usize]) -> Result<Vec<Vec<f64>>> {
+        let total = lens.iter().sum();
+        ensure!(
+            weights.len() == total,
+            SamplingError::LengthMismatch(weights.len(), total)
+        );
+        if let Some(weight) = weights.iter().find(|weight| !(weight.is_finite() && **weight >= 0.0)) {
+            return Err(SamplingError::InvalidWeight(*weight).into());
+        }
+        let mut start = 0;
+        Ok(lens
+            .iter()
+            .map(|len| {
+                start += len;
+                weights[start - len..start].to_vec()
+            })
+            .collect())
+    }
+
+    /// Reads weights stored in an archive as little-endian f32s, one per sample of its header.
+    pub(crate) fn decode_weights(bytes: &[u8], len: usize) -> Result<Vec<f64>> {
+        ensure!(
+            bytes.len() == len * 4,
+            SamplingError::LengthMismatch(bytes.len() / 4, len)
+        );
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()) as f64)
+            .collect())
+    }
+
+    pub(crate) fn with_weights(&mut self, weights: Vec<Vec<f64>>) -> &mut Self {
+        self.weights = Some(Arc::new(weights));
+        self
+    }
+
+    pub(crate) fn with_replacement(&mut self, num_samples: Option<usize>) -> &mut Self {
+        self.replacement = true;
+        self.num_samples = num_samples;
+        self
+    }
+
+    pub(crate) fn by_block(&mut self) -> &mut Self {
+        self.by_block = true;
+        self
+    }
+
+    /// Checks the weights still match the archives, which may have been replaced since they were given.
+    pub(crate) fn check(&self, lens: &[usize]) -> Result<()> {
+        if let Some(weights) = &self.weights {
+            let weight_lens: Vec<usize> = weights.iter().map(Vec::len).collect();
+            ensure!(
+                weight_lens == lens,
+                SamplingError::ArchiveMismatch(weight_lens, lens.to_vec())
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.weights.is_some() || self.replacement
+    }
+
+    fn weight_of(&self, block: &Block) -> f64 {
+        match &self.weights {
+            Some(weights) => block.ids().map(|(archive, idx)| weights[archive][idx]).sum(),
+            None => block.len() as f64,
+        }
+    }
+
+    /// Draws from `blocks`, or from their single samples, until `num_samples` or every sample with weight was drawn.
+    pub(crate) fn draw(&self, blocks: Vec<Block>, seed: u64) -> Vec<Block> {
+        let units: Vec<Block> = match self.by_block {
+            true => blocks,
+            false => blocks.iter().flat_map(|block| block.split()).collect(),
+        };
+        let weights: Vec<f64> = units.iter().map(|unit| self.weight_of(unit)).collect();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let drawn = match self.replacement {
+            true => Self::with_replacement_order(&weights, &mut rng),
+            false => Self::without_replacement_order(&weights, &mut rng),
+        };
+
+        let mut remaining = self.num_samples.unwrap_or(units.iter().map(|unit| unit.len()).sum());
+        drawn
+            .map_while(|idx| {
+                let take = units[idx].len().min(remaining);
+                remaining -= take;
+                (take > 0).then(|| units[idx].take(take))
+            })
+            .collect()
+    }
+
+    fn with_replacement_order<'a>(weights: &[f64], rng: &'a mut StdRng) -> Box<dyn Iterator<Item = usize> + 'a> {
+        match WeightedIndex::new(weights) {
+            Ok(dist) => Box::new(std::iter::repeat_with(move || dist.sample(rng))),
+            Err(_) => Box::new(std::iter::empty()),
+        }
+    }
+
+    /// Weighted permutation of the units with a positive weight, by sorting exponential arrival times.
+    fn without_replacement_order(weights: &[f64], rng: &mut StdRng) -> Box<dyn Iterator<Item = usize>> {
+        let mut keys: Vec<(f64, usize)> = weights
+            .iter()
+            .enumerate()
+            .map(|(idx, weight)| (-(1.0 - rng.gen::<f64>()).ln() / weight, idx))
+            .filter(|(key, _)| key.is_finite())
+            .collect();
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Box::new(keys.into_iter().map(|(_, idx)| idx))
+    }
+}
+
+impl Hash for Sampling {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let Some(weights) = &self.weights {
+            weights.iter().flatten().for_each(|weight| weight.to_bits().hash(state));
+        }
+        self.weights.is_some().hash(state);
+        self.replacement.hash(state);
+        self.num_samples.hash(state);
+        self.by_block.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::{Header, SampleMD};
+
+    fn blocks(n: usize, block_len: usize) -> Vec<Block> {
+        let mut header = Header::new(1024).unwrap();
+        for i in 0..n {
+            header
+                .insert(&i.to_string(), SampleMD::new(i * 10, 10).unwrap())
+                .unwrap();
+        }
+        let header = Arc::new(header);
+        (0..n)
+            .step_by(block_len)
+            .map(|start| Block::from_range(0, header.clone(), start..(start + block_len).min(n)))
+            .collect()
+    }
+
+    fn drawn_ids(sampling: &Sampling, blocks: Vec<Block>) -> Vec<usize> {
+        sampling
+            .draw(blocks, 0)
+            .iter()
+            .flat_map(|block| block.ids().map(|(_, idx)| idx))
+            .collect()
+    }
+
+    #[test]
+    fn test_without_replacement() {
+        let mut sampling = Sampling::default();
+        let weights: Vec<f64> = (0..20).map(|i| if i % 4 == 0 { 0.0 } else { i as f64 }).collect();
+        sampling.with_weights(vec![weights]);
+        let mut ids = drawn_ids(&sampling, blocks(20, 4));
+        assert_eq!(ids.len(), 15);
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 15);
+        assert!(ids.iter().all(|idx| idx % 4 != 0));
+    }
+
+    #[test]
+    fn test_with_replacement() {
+        let mut sampling = Sampling::default();
+        let mut weights = vec![0.0; 20];
+        weights[3] = 1.0;
+        weights[7] = 3.0;
+        sampling.with_weights(vec![weights]).with_replacement(Some(400));
+        let ids = drawn_ids(&sampling, blocks(20, 4));
+        assert_eq!(ids.len(), 400);
+        let sevens = ids.iter().filter(|idx| **idx == 7).count();
+        assert!((250..350).contains(&sevens), "{sevens}");
+        assert_eq!(ids.len() - sevens, ids.iter().filter(|idx| **idx == 3).count());
+    }
+
+    #[test]
+    fn test_by_block() {
+        let mut sampling = Sampling::default();
+        sampling.with_replacement(Some(10)).by_block();
+        let drawn = sampling.draw(blocks(20, 4), 0);
+        assert_eq!(drawn.iter().map(|block| block.len()).collect::<Vec<_>>(), vec![4, 4, 2]);
+    }
+
+    #[test]
+    fn test_split_weights() {
+        let split = Sampling::split_weights(&[1.0, 2.0, 3.0], &[1, 2]).unwrap();
+        assert_eq!(split, vec![vec![1.0], vec![2.0, 3.0]]);
+        let err = Sampling::split_weights(&[1.0], &[1, 2]).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SamplingError::LengthMismatch(1, 3))));
+        let err = Sampling::split_weights(&[1.0, -1.0, 0.0], &[3]).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SamplingError::InvalidWeight(_))));
+    }
+}
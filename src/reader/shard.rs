@@ -0,0 +1,148 @@
+use std::str::FromStr;
+
+use color_eyre::eyre::{bail, Error, Result};
+
+use crate::reader::block::Block;
+use crate::reader::collector::CollectorError;
+
+/// How ranks that were assigned a different number of samples are evened out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ShardMode {
+    /// Every rank reads exactly the blocks it was assigned.
+    #[default]
+    Uneven,
+    /// Ranks are cut down to the sample count of the smallest one.
+    DropRemainder,
+    /// Ranks are filled up to the sample count of the largest one by repeating their own samples.
+    Pad,
+}
+
+impl FromStr for ShardMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "uneven" => Ok(Self::Uneven),
+            "drop_remainder" => Ok(Self::DropRemainder),
+            "pad" => Ok(Self::Pad),
+            _ => bail!(CollectorError::InvalidShardMode(s.to_string())),
+        }
+    }
+}
+
+fn samples_of(blocks: &[Block]) -> usize {
+    blocks.iter().map(|block| block.len()).sum()
+}
+
+/// Splits blocks over ranks, either round robin or greedily to the rank with the fewest bytes so far.
+fn assign(blocks: Vec<Block>, world_size: u16, by_size: bool) -> Vec<Vec<Block>> {
+    let mut shards = vec![Vec::new(); world_size as usize];
+    let mut loads = vec![0usize; world_size as usize];
+    for (i, block) in blocks.into_iter().enumerate() {
+        let rank = match by_size {
+            true => (0..loads.len()).min_by_key(|rank| loads[*rank]).unwrap(),
+            false => i % world_size as usize,
+        };
+        loads[rank] += block.byte_size();
+        shards[rank].push(block);
+    }
+    shards
+}
+
+fn truncate(blocks: Vec<Block>, target: usize) -> Vec<Block> {
+    let mut remaining = target;
+    blocks
+        .into_iter()
+        .map_while(|block| {
+            let take = block.len().min(remaining);
+            remaining -= take;
+            (take > 0).then(|| block.take(take))
+        })
+        .collect()
+}
+
+fn pad(mut blocks: Vec<Block>, fallback: &[Block], target: usize) -> Vec<Block> {
+    let source = match blocks.is_empty() {
+        true => fallback.to_vec(),
+        false => blocks.clone(),
+    };
+    let missing = target - samples_of(&blocks);
+    blocks.extend(truncate(source.into_iter().cycle().take(missing).collect(), missing));
+    blocks
+}
+
+/// Blocks read by `rank`, `blocks` must be in the same order on every rank.
+pub(crate) fn shard_blocks(
+    blocks: Vec<Block>,
+    rank: u16,
+    world_size: u16,
+    mode: ShardMode,
+    by_size: bool,
+) -> Vec<Block> {
+    let all = blocks.clone();
+    let mut shards = assign(blocks, world_size, by_size);
+    let counts: Vec<usize> = shards.iter().map(|shard| samples_of(shard)).collect();
+    let shard = shards.swap_remove(rank as usize);
+    match mode {
+        ShardMode::Uneven => shard,
+        ShardMode::DropRemainder => truncate(shard, *counts.iter().min().unwrap()),
+        ShardMode::Pad => pad(shard, &all, *counts.iter().max().unwrap()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::header::{Header, SampleMD};
+
+    fn blocks(lengths: &[usize]) -> Vec<Block> {
+        let mut header = Header::new(1024).unwrap();
+        let n: usize = lengths.iter().sum();
+        for i in 0..n {
+            header
+                .insert(&i.to_string(), SampleMD::new(i * 10, 10).unwrap())
+                .unwrap();
+        }
+        let header = Arc::new(header);
+        let mut start = 0;
+        lengths
+            .iter()
+            .map(|len| {
+                start += len;
+                Block::from_range(0, header.clone(), start - len..start)
+            })
+            .collect()
+    }
+
+    fn counts(lengths: &[usize], world_size: u16, mode: ShardMode, by_size: bool) -> Vec<usize> {
+        (0..world_size)
+            .map(|rank| samples_of(&shard_blocks(blocks(lengths), rank, world_size, mode, by_size)))
+            .collect()
+    }
+
+    #[test]
+    fn test_shard_modes() {
+        let lengths = [4, 4, 4, 4, 3];
+        assert_eq!(counts(&lengths, 2, ShardMode::Uneven, false), vec![11, 8]);
+        assert_eq!(counts(&lengths, 2, ShardMode::DropRemainder, false), vec![8, 8]);
+        assert_eq!(counts(&lengths, 2, ShardMode::Pad, false), vec![11, 11]);
+        assert_eq!(counts(&lengths, 4, ShardMode::DropRemainder, false), vec![4, 4, 4, 4]);
+        assert_eq!(counts(&lengths, 8, ShardMode::Pad, false), vec![4; 8]);
+    }
+
+    #[test]
+    fn test_shard_by_size() {
+        let lengths = [8, 1, 1, 1, 1, 8];
+        assert_eq!(counts(&lengths, 2, ShardMode::Uneven, false), vec![10, 10]);
+        assert_eq!(counts(&lengths, 2, ShardMode::Uneven, true), vec![8, 12]);
+        assert_eq!(counts(&[8, 4, 4], 2, ShardMode::Uneven, true), vec![8, 8]);
+    }
+
+    #[test]
+    fn test_shard_mode_from_str() {
+        assert_eq!("pad".parse::<ShardMode>().unwrap(), ShardMode::Pad);
+        assert!("even".parse::<ShardMode>().is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use color_eyre::eyre::Result;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::reader::block::YieldedId;
+use crate::reader::Sample;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum BufferCriteria {
+    Size(usize),
+    Count(usize),
+}
+
+impl BufferCriteria {
+    fn is_full(&self, buffer: &[(YieldedId, Sample)], size: usize) -> bool {
+        match self {
+            BufferCriteria::Size(n) => size >= *n,
+            BufferCriteria::Count(n) => buffer.len() >= *n,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BufferState {
+    pub(crate) ids: Vec<YieldedId>,
+    rng: ChaCha12Rng,
+}
+
+/// Mixes samples across block boundaries by holding a window of them and yielding one at random.
+pub(crate) struct ShuffleBuffer {
+    criteria: BufferCriteria,
+    buffer: Vec<(YieldedId, Sample)>,
+    size: usize,
+    rng: ChaCha12Rng,
+}
+
+impl ShuffleBuffer {
+    pub(crate) fn new(criteria: BufferCriteria, seed: u64) -> Self {
+        Self {
+            criteria,
+            buffer: Vec::new(),
+            size: 0,
+            rng: ChaCha12Rng::seed_from_u64(seed),
+        }
+    }
+
+    /// Rebuilds a buffer from a saved state, `samples` must be in the order of `state.ids`.
+    pub(crate) fn restore(criteria: BufferCriteria, state: &BufferState, samples: Vec<Sample>) -> Self {
+        let buffer: Vec<(YieldedId, Sample)> = state.ids.iter().copied().zip(samples).collect();
+        Self {
+            criteria,
+            size: buffer.iter().map(|(_, (_, value))| value.len()).sum(),
+            buffer,
+            rng: state.rng.clone(),
+        }
+    }
+
+    pub(crate) fn state(&self) -> BufferState {
+        BufferState {
+            ids: self.buffer.iter().map(|(id, _)| *id).collect(),
+            rng: self.rng.clone(),
+        }
+    }
+
+    /// Tops the buffer up from `iter`, stopping at the first error.
+    fn fill<I>(&mut self, iter: &mut I) -> Result<()>
+    where
+        I: Iterator<Item = Result<(YieldedId, Sample)>>,
+    {
+        while !self.criteria.is_full(&self.buffer, self.size) {
+            match iter.next() {
+                Some(item) => {
+                    let (id, sample) = item?;
+                    self.size += sample.1.len();
+                    self.buffer.push((id, sample));
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn next<I>(&mut self, iter: &mut I) -> Option<Result<(YieldedId, Sample)>>
+    where
+        I: Iterator<Item = Result<(YieldedId, Sample)>>,
+    {
+        if let Err(err) = self.fill(iter) {
+            return Some(Err(err));
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let idx = self.rng.gen_range(0..self.buffer.len());
+        let (id, sample) = self.buffer.swap_remove(idx);
+        self.size -= sample.1.len();
+        Some(Ok((id, sample)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn samples(n: usize) -> Vec<(YieldedId, Sample)> {
+        (0..n)
+            .map(|i| ((0, (0, i)), (i.to_string(), Bytes::from(vec![0u8; 10]))))
+            .collect()
+    }
+
+    fn drain(buffer: &mut ShuffleBuffer, iter: &mut impl Iterator<Item = Result<(YieldedId, Sample)>>) -> Vec<Sample> {
+        std::iter::from_fn(|| buffer.next(iter))
+            .map(|item| item.unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn test_shuffle_buffer_by_count() {
+        let mut iter = samples(100).into_iter().map(Ok);
+        let shuffled = drain(&mut ShuffleBuffer::new(BufferCriteria::Count(16), 0), &mut iter);
+        assert_eq!(shuffled.len(), 100);
+        let mut keys: Vec<usize> = shuffled.iter().map(|(key, _)| key.parse().unwrap()).collect();
+        assert_ne!(keys, (0..100).collect::<Vec<_>>());
+        // A sample can't be yielded before it has entered the window.
+        assert!(keys.iter().enumerate().all(|(i, key)| *key < i + 16));
+        keys.sort();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_shuffle_buffer_by_size() {
+        let mut iter = samples(100).into_iter().map(Ok);
+        let mut buffer = ShuffleBuffer::new(BufferCriteria::Size(45), 0);
+        buffer.next(&mut iter).unwrap().unwrap();
+        assert_eq!(buffer.buffer.len(), 4);
+        assert_eq!(buffer.size, 40);
+        assert_eq!(drain(&mut buffer, &mut iter).len(), 99);
+    }
+
+    #[test]
+    fn test_shuffle_buffer_restore() {
+        let all = samples(100);
+        let mut iter = all.clone().into_iter().map(Ok);
+        let mut buffer = ShuffleBuffer::new(BufferCriteria::Count(16), 0);
+        (0..30).for_each(|_| {
+            buffer.next(&mut iter).unwrap().unwrap();
+        });
+
+        let state = buffer.state();
+        let restored_samples = state.ids.iter().map(|(_, (_, idx))| all[*idx].1.clone()).collect();
+        let mut restored = ShuffleBuffer::restore(BufferCriteria::Count(16), &state, restored_samples);
+        let mut restored_iter = iter.clone();
+        assert_eq!(drain(&mut buffer, &mut iter), drain(&mut restored, &mut restored_iter));
+    }
+}
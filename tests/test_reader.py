@@ -0,0 +1,85 @@
+import pickle
+
+import pytest
+
+import rand_archive as ra
+
+
+def write_archive(path, count=64, size=10):
+    writer = ra.Writer(str(path))
+    for i in range(count):
+        writer.write(f'{i:06}', bytes([i]) * size)
+    writer.close()
+    return str(path)
+
+
+def loader_keys(reader):
+    data = pytest.importorskip('torch.utils.data')
+
+    class Keys(data.IterableDataset):
+        def __iter__(self):
+            return (key for key, _ in reader)
+
+    loader = data.DataLoader(Keys(), batch_size=None, num_workers=2, multiprocessing_context='fork')
+    return sorted(loader)
+
+
+def test_buffered_reader_in_workers(tmp_path):
+    reader = ra.Reader().open_file(write_archive(tmp_path / 'dummy.raa')).by_count(4).with_buffering(4)
+    # Starts the read threads in this process before the workers are forked.
+    assert len(list(reader)) == 64
+    assert loader_keys(reader) == [f'{i:06}' for i in range(64)]
+
+
+def test_dataset_pickles(tmp_path):
+    ds = ra.Dataset(write_archive(tmp_path / 'dummy.raa'), cache_size=1 << 20)
+    assert ds[3] == ('000003', bytes([3]) * 10)
+    restored = pickle.loads(pickle.dumps(ds))
+    assert len(restored) == len(ds)
+    assert [restored[i] for i in range(len(ds))] == [ds[i] for i in range(len(ds))]
+    assert restored['000042'] == ds['000042']
+
+
+def test_resume_from_pickled_state(tmp_path):
+    reader = ra.Reader().open_file(write_archive(tmp_path / 'dummy.raa')).by_count(4).with_shuffling(7)
+    expected = list(reader)
+    it = iter(reader)
+    taken = [next(it) for _ in range(21)]
+    state = pickle.loads(pickle.dumps(it.state()))
+    assert state.epoch == it.state().epoch
+    assert taken + list(reader.iter_from(state)) == expected
+
+
+def test_reader_in_workers(tmp_path):
+    reader = ra.Reader().open_file(write_archive(tmp_path / 'dummy.raa')).by_count(4).with_shuffling(7)
+    # Every sample is read by exactly one of the workers.
+    assert loader_keys(reader) == [f'{i:06}' for i in range(64)]
+
+
+def test_mixer_resumes_from_pickled_state(tmp_path):
+    first = ra.Reader().open_file(write_archive(tmp_path / 'first.raa')).with_shuffling(1)
+    second = ra.Reader().open_file(write_archive(tmp_path / 'second.raa', count=16)).with_shuffling(2)
+    mixer = ra.Mixer([(first, 3.0), (second, 1.0)], seed=0)
+    expected = list(mixer)
+    it = iter(mixer)
+    taken = [next(it) for _ in range(30)]
+    state = pickle.loads(pickle.dumps(it.state()))
+    assert taken + list(mixer.iter_from(state)) == expected
+
+
+def test_buckets_resume_from_pickled_state(tmp_path):
+    reader = ra.Reader().open_file(write_archive(tmp_path / 'dummy.raa')).with_shuffling(7)
+    expected = list(reader.iter_buckets(4, boundaries=[8, 16]))
+    it = reader.iter_buckets(4, boundaries=[8, 16])
+    taken = [next(it) for _ in range(5)]
+    state = pickle.loads(pickle.dumps(it.state()))
+    assert taken + list(reader.iter_buckets(4, boundaries=[8, 16], state=state)) == expected
+
+
+def test_packing_resumes_from_pickled_state(tmp_path):
+    reader = ra.Reader().open_file(write_archive(tmp_path / 'dummy.raa')).with_shuffling(7)
+    expected = list(reader.iter_packed(8, separator=0, segments=True))
+    it = reader.iter_packed(8, separator=0, segments=True)
+    taken = [next(it) for _ in range(13)]
+    state = pickle.loads(pickle.dumps(it.state()))
+    assert taken + list(reader.iter_packed(8, separator=0, segments=True, state=state)) == expected